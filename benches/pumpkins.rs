@@ -0,0 +1,46 @@
+use std::rc::Rc;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use pumpkins::graph::{LookupTable, PumpkinPatch};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+const SIZES: [u16; 4] = [10, 20, 40, 60];
+
+fn lookup_table(c: &mut Criterion) {
+    let mut group = c.benchmark_group("LookupTable::new");
+    group.sample_size(10);
+    for size in SIZES {
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter(|| LookupTable::new(size))
+        });
+    }
+    group.finish();
+}
+
+fn fill(c: &mut Criterion) {
+    let mut group = c.benchmark_group("PumpkinPatch fill");
+    for size in SIZES {
+        // The table and the insert order are built once, outside of the timed region
+        let table = Rc::new(LookupTable::new(size));
+        let mut order: Vec<_> = (0..size)
+            .flat_map(|y| (0..size).map(move |x| (x, y)))
+            .collect();
+        order.shuffle(&mut StdRng::seed_from_u64(0));
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &order, |b, order| {
+            b.iter_batched_ref(
+                || PumpkinPatch::new(size, table.clone()),
+                |pumpkins| {
+                    for &(x, y) in order {
+                        pumpkins.add(x, y).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lookup_table, fill);
+criterion_main!(benches);
@@ -0,0 +1,42 @@
+//! Runs a byte stream as add and remove operations on a 6x6 patch, checking
+//! `PumpkinPatch::validate` after every step.
+//!
+//! Each byte is one operation. The low 7 bits pick the cell `y * 6 + x` (modulo 36) and the
+//! high bit removes the cell instead of adding it. Run it from the repository root with a
+//! nightly toolchain:
+//!
+//! ```text
+//! cargo install cargo-fuzz
+//! cargo +nightly fuzz run add_remove
+//! ```
+//!
+//! `corpus/add_remove` holds seeds, including the insert order of `test_merge_3`.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pumpkins::graph::{AddError, PumpkinPatch};
+
+const SIZE: u16 = 6;
+
+fuzz_target!(|ops: &[u8]| {
+    let mut pumpkins = PumpkinPatch::new_make_table(SIZE);
+
+    for &op in ops {
+        let cell = (op & 0x7f) as u16 % (SIZE * SIZE);
+        let (x, y) = (cell % SIZE, cell / SIZE);
+
+        if op & 0x80 == 0 {
+            match pumpkins.add(x, y) {
+                Ok(square) => assert_eq!(pumpkins.get_square_at(x, y), Some(square)),
+                Err(err) => assert_eq!(err, AddError::AlreadyFilled),
+            }
+        } else {
+            pumpkins.remove(x, y);
+            assert_eq!(pumpkins.get(x, y), None);
+        }
+
+        if let Err(err) = pumpkins.validate() {
+            panic!("{} after {:#04x}\n{}", err, op, pumpkins);
+        }
+    }
+});
@@ -0,0 +1,248 @@
+use alloc::{vec, vec::Vec};
+use core::ops::Range;
+
+use bit_vec::BitVec;
+
+/// A fixed length set of bits packed into 64 bit words
+///
+/// Bits past `len` in the last word are always clear, so whole words can be compared and
+/// counted without masking.
+#[derive(Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct Bitmap {
+    words: Vec<u64>,
+    len: usize,
+}
+
+// `clone_from` reuses the words already allocated, the derived one wouldn't
+impl Clone for Bitmap {
+    fn clone(&self) -> Self {
+        Self {
+            words: self.words.clone(),
+            len: self.len,
+        }
+    }
+
+    fn clone_from(&mut self, source: &Self) {
+        self.words.clone_from(&source.words);
+        self.len = source.len;
+    }
+}
+
+impl Bitmap {
+    /// Creates a bitmap of `len` clear bits
+    pub fn new(len: usize) -> Self {
+        Self {
+            words: vec![0; len.div_ceil(64)],
+            len,
+        }
+    }
+
+    /// Creates a bitmap of `len` bits where bit `i` is `f(i)`
+    pub fn from_fn(len: usize, mut f: impl FnMut(usize) -> bool) -> Self {
+        let mut bitmap = Self::new(len);
+        for i in 0..len {
+            if f(i) {
+                bitmap.set(i, true);
+            }
+        }
+        bitmap
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        assert!(i < self.len, "bit {} out of range for {} bits", i, self.len);
+        self.words[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    pub fn set(&mut self, i: usize, value: bool) {
+        assert!(i < self.len, "bit {} out of range for {} bits", i, self.len);
+        let mask = 1 << (i % 64);
+        if value {
+            self.words[i / 64] |= mask;
+        } else {
+            self.words[i / 64] &= !mask;
+        }
+    }
+
+    /// Clears every bit, keeping the length
+    pub fn clear(&mut self) {
+        self.words.fill(0);
+    }
+
+    /// Clears every bit and changes the length to `len`, reusing the allocation when it's
+    /// large enough
+    pub fn reset(&mut self, len: usize) {
+        self.words.clear();
+        self.words.resize(len.div_ceil(64), 0);
+        self.len = len;
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// Returns true if no bit is set
+    pub fn none(&self) -> bool {
+        self.words.iter().all(|&w| w == 0)
+    }
+
+    /// Returns true if every bit set in `self` is also set in `other`
+    pub fn is_subset(&self, other: &Bitmap) -> bool {
+        debug_assert_eq!(self.len, other.len);
+        self.words
+            .iter()
+            .zip(&other.words)
+            .all(|(&a, &b)| a & !b == 0)
+    }
+
+    /// [`Bitmap::is_subset`] treating bit `except` as set in `other`, only the words holding
+    /// `bits` are compared
+    ///
+    /// Callers pass the range `self` is known to be clear outside of, so checking a small set
+    /// against a large one doesn't have to read every word.
+    pub fn is_subset_except(&self, other: &Bitmap, except: usize, bits: Range<usize>) -> bool {
+        debug_assert_eq!(self.len, other.len);
+        if bits.is_empty() {
+            return true;
+        }
+
+        let words = bits.start / 64..(bits.end - 1) / 64 + 1;
+        let (except_word, except_mask) = (except / 64, 1 << (except % 64));
+        self.words[words.clone()]
+            .iter()
+            .zip(&other.words[words.clone()])
+            .zip(words)
+            .all(|((&a, &b), i)| {
+                let b = if i == except_word { b | except_mask } else { b };
+                a & !b == 0
+            })
+    }
+
+    /// The backing words, bit `i` is bit `i % 64` of word `i / 64`
+    pub fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    /// Packs the bits into bytes the same way as [`BitVec::to_bytes`], most significant bit
+    /// first
+    pub fn to_bytes(&self) -> Vec<u8> {
+        (0..self.len.div_ceil(8))
+            .map(|byte| {
+                (0..8)
+                    .filter(|bit| {
+                        let i = byte * 8 + bit;
+                        i < self.len && self.get(i)
+                    })
+                    .fold(0, |acc, bit| acc | (0x80 >> bit))
+            })
+            .collect()
+    }
+
+    /// Inverse of [`Bitmap::to_bytes`], returns `None` if `bytes` doesn't hold exactly `len`
+    /// bits rounded up to a whole byte
+    pub fn from_bytes(len: usize, bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != len.div_ceil(8) {
+            return None;
+        }
+        Some(Self::from_fn(len, |i| {
+            bytes[i / 8] & (0x80 >> (i % 8)) != 0
+        }))
+    }
+}
+
+impl core::ops::Index<usize> for Bitmap {
+    type Output = bool;
+
+    fn index(&self, i: usize) -> &bool {
+        if self.get(i) {
+            &true
+        } else {
+            &false
+        }
+    }
+}
+
+impl From<&BitVec> for Bitmap {
+    fn from(bits: &BitVec) -> Self {
+        Self::from_fn(bits.len(), |i| bits[i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_set() {
+        let mut bitmap = Bitmap::new(130);
+        assert_eq!(bitmap.words().len(), 3);
+        assert!(bitmap.none());
+
+        for i in [0, 63, 64, 129] {
+            bitmap.set(i, true);
+            assert!(bitmap[i]);
+        }
+        assert_eq!(bitmap.count_ones(), 4);
+        bitmap.set(63, false);
+        assert!(!bitmap.get(63));
+        assert_eq!(bitmap.count_ones(), 3);
+
+        bitmap.clear();
+        assert!(bitmap.none());
+        assert_eq!(bitmap.len(), 130);
+
+        bitmap.set(129, true);
+        bitmap.reset(10);
+        assert_eq!(bitmap, Bitmap::new(10));
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn test_out_of_range() {
+        Bitmap::new(70).get(70);
+    }
+
+    #[test]
+    fn test_subset() {
+        // matches the `BitVec::and` check it replaces
+        for _ in 0..200 {
+            let len = rand::random::<usize>() % 200 + 1;
+            let a = BitVec::from_fn(len, |_| rand::random::<f64>() < 0.1);
+            let b = BitVec::from_fn(len, |_| rand::random::<f64>() < 0.9);
+            let except = rand::random::<usize>() % len;
+
+            let mut scratch = a.clone();
+            let subset = !scratch.and(&b);
+            scratch.clone_from(&a);
+            scratch.set(except, false);
+            let subset_except = !scratch.and(&b);
+
+            let (a, b) = (Bitmap::from(&a), Bitmap::from(&b));
+            assert_eq!(a.is_subset(&b), subset);
+            assert_eq!(a.is_subset_except(&b, except, 0..len), subset_except);
+
+            // only the words holding the set bits of `a` matter
+            let start = (0..len).find(|&i| a[i]).unwrap_or(0);
+            let end = (0..len).rfind(|&i| a[i]).map_or(0, |i| i + 1);
+            assert_eq!(a.is_subset_except(&b, except, start..end), subset_except);
+        }
+    }
+
+    #[test]
+    fn test_bytes() {
+        for len in [0, 1, 7, 8, 9, 64, 100] {
+            let bits = BitVec::from_fn(len, |i| i % 3 == 0);
+            let bitmap = Bitmap::from(&bits);
+            assert_eq!(bitmap.to_bytes(), bits.to_bytes());
+            assert_eq!(Bitmap::from_bytes(len, &bits.to_bytes()), Some(bitmap));
+        }
+        assert_eq!(Bitmap::from_bytes(9, &[0]), None);
+    }
+}
@@ -1,58 +1,229 @@
-use std::{num::NonZeroU16, rc::Rc};
+use alloc::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    format,
+    rc::Rc,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{
+    num::{NonZeroU16, NonZeroU32},
+    ops::{Deref, Range},
+};
 
 use bit_vec::BitVec;
+use rand::{seq::SliceRandom, Rng};
+
+use crate::bitmap::Bitmap;
+
+// Prints the steps of the merge search, only when the `trace-merge` feature is enabled
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "trace-merge") {
+            #[cfg(feature = "std")]
+            std::println!($($arg)*);
+            // keeps the arguments used in `no_std` builds
+            #[cfg(not(feature = "std"))]
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "RawSquare"))]
 pub struct Square {
     x: u16,
     y: u16,
     size: NonZeroU16,
 }
 
+/// Unvalidated form of [`Square`] used to reject zero sized squares during deserialization
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawSquare {
+    x: u16,
+    y: u16,
+    size: u16,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<RawSquare> for Square {
+    type Error = &'static str;
+
+    fn try_from(raw: RawSquare) -> Result<Self, Self::Error> {
+        Square::try_new(raw.x, raw.y, raw.size).ok_or("square size must be non-zero")
+    }
+}
+
 impl Square {
-    fn new(x: u16, y: u16, size: u16) -> Self {
-        Self {
+    /// Creates a new square with its bottom-left cell at `(x, y)`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0, use [`Square::try_new`] to validate untrusted input
+    pub fn new(x: u16, y: u16, size: u16) -> Self {
+        Self::try_new(x, y, size).expect("square size must be non-zero")
+    }
+
+    /// Creates a new square with its bottom-left cell at `(x, y)`, returns `None` if `size` is 0
+    pub fn try_new(x: u16, y: u16, size: u16) -> Option<Self> {
+        Some(Self {
             x,
             y,
-            size: NonZeroU16::new(size).unwrap(),
-        }
+            size: NonZeroU16::new(size)?,
+        })
+    }
+
+    pub fn x(&self) -> u16 {
+        self.x
     }
 
-    fn size(&self) -> u16 {
+    pub fn y(&self) -> u16 {
+        self.y
+    }
+
+    pub fn size(&self) -> u16 {
         self.size.get()
     }
 
-    fn bitmap(&self, grid_size: u16) -> BitVec {
-        let grid_size = grid_size as usize;
-        BitVec::from_fn(grid_size * grid_size, |i| {
-            let bx = i % grid_size;
-            let by = i / grid_size;
-            self.contains(bx as u16, by as u16)
-        })
+    /// The bottom-left cell of the square
+    pub fn origin(&self) -> (u16, u16) {
+        (self.x, self.y)
+    }
+
+    /// The corner cells of the square in the order bottom-left, bottom-right, top-left, top-right
+    pub fn corners(&self) -> [(u16, u16); 4] {
+        let far_x = self.x + self.size() - 1;
+        let far_y = self.y + self.size() - 1;
+        [
+            (self.x, self.y),
+            (far_x, self.y),
+            (self.x, far_y),
+            (far_x, far_y),
+        ]
+    }
+
+    /// Number of grid cells covered by the square
+    pub fn cell_count(&self) -> u32 {
+        self.size() as u32 * self.size() as u32
+    }
+
+    /// Iterates over every cell covered by the square in row-major order
+    pub fn iter_cells(&self) -> impl Iterator<Item = (u16, u16)> {
+        let (x0, y0, size) = (self.x, self.y, self.size());
+        (y0..y0 + size).flat_map(move |y| (x0..x0 + size).map(move |x| (x, y)))
+    }
+
+    /// The border ring of the square in clockwise order (north is +y) starting from the
+    /// bottom-left corner. Each corner appears once.
+    pub fn perimeter_cells(&self) -> Vec<(u16, u16)> {
+        let (x0, y0) = (self.x, self.y);
+        let (x1, y1) = (self.x + self.size() - 1, self.y + self.size() - 1);
+
+        if self.size() == 1 {
+            return vec![(x0, y0)];
+        }
+
+        let mut cells = Vec::with_capacity(4 * self.size() as usize - 4);
+        // west side going north
+        cells.extend((y0..y1).map(|y| (x0, y)));
+        // north side going east
+        cells.extend((x0..x1).map(|x| (x, y1)));
+        // east side going south
+        cells.extend((y0 + 1..=y1).rev().map(|y| (x1, y)));
+        // south side going west
+        cells.extend((x0 + 1..=x1).rev().map(|x| (x, y0)));
+
+        cells
+    }
+
+    fn bitmap(&self, width: u16, height: u16) -> Bitmap {
+        let mut bitmap = Bitmap::default();
+        self.bitmap_into(width, height, &mut bitmap);
+        bitmap
+    }
+
+    // `bitmap` written into a caller's buffer, which is cleared and resized to the grid first
+    fn bitmap_into(&self, width: u16, height: u16, out: &mut Bitmap) {
+        out.reset(width as usize * height as usize);
+        for (x, y) in self.iter_cells() {
+            if x < width && y < height {
+                out.set(y as usize * width as usize + x as usize, true);
+            }
+        }
     }
 
     fn contains(&self, x: u16, y: u16) -> bool {
         self.x <= x && x < self.x + self.size.get() && self.y <= y && y < self.y + self.size.get()
     }
 
+    // Returns true if the square lies entirely inside a `width` x `height` grid
+    fn fits(&self, width: u16, height: u16) -> bool {
+        self.x as u32 + self.size() as u32 <= width as u32
+            && self.y as u32 + self.size() as u32 <= height as u32
+    }
+
+    /// Returns true if every cell of `other` is inside `self`
+    pub fn contains_square(&self, other: &Square) -> bool {
+        self.x <= other.x
+            && self.y <= other.y
+            && other.x + other.size() <= self.x + self.size()
+            && other.y + other.size() <= self.y + self.size()
+    }
+
+    /// Returns true if the two squares share at least one cell. Squares that only touch along an
+    /// edge do not overlap.
+    pub fn overlaps(&self, other: &Square) -> bool {
+        self.intersection(other).is_some()
+    }
+
+    /// Returns the overlapping rectangle as `(x, y, w, h)`, or `None` if the squares don't share
+    /// any cells
+    pub fn intersection(&self, other: &Square) -> Option<(u16, u16, u16, u16)> {
+        let x0 = self.x.max(other.x);
+        let y0 = self.y.max(other.y);
+        let x1 = (self.x + self.size()).min(other.x + other.size());
+        let y1 = (self.y + self.size()).min(other.y + other.size());
+
+        if x0 < x1 && y0 < y1 {
+            Some((x0, y0, x1 - x0, y1 - y0))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the smallest axis-aligned box `(x, y, w, h)` covering both squares
+    pub fn bounding_union(&self, other: &Square) -> (u16, u16, u16, u16) {
+        let x0 = self.x.min(other.x);
+        let y0 = self.y.min(other.y);
+        let x1 = (self.x + self.size()).max(other.x + other.size());
+        let y1 = (self.y + self.size()).max(other.y + other.size());
+
+        (x0, y0, x1 - x0, y1 - y0)
+    }
+
     // Returns { sq : Sqaure | sq.sz = self.sz + 1 && self ⊂ sq }
-    fn next_larger_squares(&self, grid_size: u16) -> Vec<Square> {
+    fn next_larger_squares(&self, width: u16, height: u16) -> Vec<Square> {
         let mut squares = Vec::new();
-        if self.size.get() == grid_size {
+        if self.size.get() >= width.min(height) {
             return squares;
         }
 
         let new_size = self.size.get() + 1;
 
-        let min_x = self.x.saturating_sub(new_size - 1);
-        let max_x = if self.x + new_size <= grid_size {
+        // A square touching the far edge can only grow back towards the origin. It can't also
+        // touch the near edge, it would span the grid and have returned above.
+        let min_x = self.x.saturating_sub(1);
+        let max_x = if self.x + new_size <= width {
             self.x
         } else {
             self.x - 1
         };
 
-        let min_y = self.y.saturating_sub(new_size - 1);
-        let max_y = if self.y + new_size <= grid_size {
+        let min_y = self.y.saturating_sub(1);
+        let max_y = if self.y + new_size <= height {
             self.y
         } else {
             self.y - 1
@@ -61,7 +232,7 @@ impl Square {
         for x in min_x..=max_x {
             for y in min_y..=max_y {
                 let sq = Square::new(x, y, new_size);
-                debug_assert!(sq.contains(self.x, self.y));
+                debug_assert!(sq.contains_square(self));
                 squares.push(sq);
             }
         }
@@ -87,34 +258,201 @@ impl Square {
         Some(squares)
     }
 
+    // Toroidal version of `next_larger_squares`, the larger squares may wrap around the edges of
+    // the grid so every square grows in all 4 directions until it spans the grid
+    fn next_larger_squares_toroidal(&self, width: u16, height: u16) -> Vec<Square> {
+        let new_size = self.size.get() + 1;
+        if new_size > width.min(height) {
+            return Vec::new();
+        }
+
+        let xs = [wrap(self.x as i32 - 1, width), self.x];
+        let ys = [wrap(self.y as i32 - 1, height), self.y];
+
+        xs.iter()
+            .flat_map(|&x| ys.iter().map(move |&y| Square::new(x, y, new_size)))
+            .collect()
+    }
+
+    // Toroidal version of `next_smaller_squares`
+    fn next_smaller_squares_toroidal(&self, width: u16, height: u16) -> Option<[Square; 4]> {
+        if self.size.get() == 1 {
+            return None;
+        }
+
+        let directions = [(0, 0), (1, 0), (0, 1), (1, 1)];
+        let squares = directions.map(|(dx, dy)| {
+            let x = wrap(self.x as i32 + dx, width);
+            let y = wrap(self.y as i32 + dy, height);
+            Square::new(x, y, self.size.get() - 1)
+        });
+
+        Some(squares)
+    }
+
+    // Bitmap of the cells covered on a torus, cells past the far edges wrap back to 0
+    fn bitmap_toroidal(&self, width: u16, height: u16) -> Bitmap {
+        let mut bitmap = Bitmap::default();
+        self.bitmap_toroidal_into(width, height, &mut bitmap);
+        bitmap
+    }
+
+    fn bitmap_toroidal_into(&self, width: u16, height: u16, out: &mut Bitmap) {
+        out.reset(width as usize * height as usize);
+        for (x, y) in self.iter_cells_toroidal(width, height) {
+            out.set(y as usize * width as usize + x as usize, true);
+        }
+    }
+
+    // `iter_cells` with the coordinates wrapped into a `width` x `height` torus
+    fn iter_cells_toroidal(&self, width: u16, height: u16) -> impl Iterator<Item = (u16, u16)> {
+        let (x0, y0, size) = (self.x as i32, self.y as i32, self.size() as i32);
+        (y0..y0 + size)
+            .flat_map(move |y| (x0..x0 + size).map(move |x| (wrap(x, width), wrap(y, height))))
+    }
+
+    /// Returns the square shifted by `(dx, dy)` if it still fits entirely inside the grid
+    pub fn translate(&self, dx: i32, dy: i32, grid_size: u16) -> Option<Square> {
+        let x = self.x as i64 + dx as i64;
+        let y = self.y as i64 + dy as i64;
+        let size = self.size() as i64;
+        let grid_size = grid_size as i64;
+
+        if x < 0 || y < 0 || x + size > grid_size || y + size > grid_size {
+            return None;
+        }
+
+        Some(Square::new(x as u16, y as u16, self.size()))
+    }
+
+    // Returns { sq : Square | sq.sz = self.sz && sq is self shifted by one cell in x or y }
+    //
+    // Only squares that stay inside the grid are returned, so there are at most 4 such squares
+    fn next_same_size_neighbors(&self, width: u16, height: u16) -> Vec<Square> {
+        let size = self.size.get();
+        let mut squares = Vec::with_capacity(4);
+
+        if self.x > 0 {
+            squares.push(Square::new(self.x - 1, self.y, size));
+        }
+        if self.x + size < width {
+            squares.push(Square::new(self.x + 1, self.y, size));
+        }
+        if self.y > 0 {
+            squares.push(Square::new(self.x, self.y - 1, size));
+        }
+        if self.y + size < height {
+            squares.push(Square::new(self.x, self.y + 1, size));
+        }
+
+        squares
+    }
+
     /// Perfect hash function for square objects
-    fn idx(&self, grid_size: usize) -> usize {
-        debug_assert!(self.x < grid_size as u16);
-        debug_assert!(self.y < grid_size as u16);
+    ///
+    /// Maps every square with `x < width`, `y < height` and `size <= min(width, height)` into
+    /// `0..width * height * min(width, height)`
+    fn idx(&self, width: usize, height: usize) -> usize {
+        debug_assert!((self.x as usize) < width);
+        debug_assert!((self.y as usize) < height);
         debug_assert!(self.size() > 0);
+        debug_assert!(self.size() as usize <= width.min(height));
 
         let size = self.size.get() as usize;
         let x = self.x as usize;
         let y = self.y as usize;
 
-        x + y * grid_size + (size - 1) * grid_size * grid_size
+        let idx = x + y * width + (size - 1) * width * height;
+        debug_assert!(idx < width * height * width.min(height));
+        idx
     }
 
-    fn from_index(idx: usize, grid_size: usize) -> Square {
-        let size = idx / (grid_size * grid_size);
-        let idx = idx % (grid_size * grid_size);
+    /// Inverse of [`Square::idx`]
+    fn from_index(idx: usize, width: usize, height: usize) -> Square {
+        debug_assert!(idx < width * height * width.min(height));
 
-        let y = idx / grid_size;
-        let x = idx % grid_size;
+        let size = idx / (width * height);
+        let idx = idx % (width * height);
+
+        let y = idx / width;
+        let x = idx % width;
 
         Square::new(x as u16, y as u16, size as u16 + 1)
     }
 }
 
+// Wraps a coordinate into `0..n`
+fn wrap(v: i32, n: u16) -> u16 {
+    v.rem_euclid(n as i32) as u16
+}
+
+/// How the edges of the grid behave
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Topology {
+    /// Pumpkins have to lie inside the grid
+    #[default]
+    Bounded,
+    /// Opposite edges of the grid are joined, so pumpkins can wrap around them
+    Toroidal,
+}
+
+impl Topology {
+    fn larger_squares(self, square: Square, width: u16, height: u16) -> Vec<Square> {
+        match self {
+            Topology::Bounded => square.next_larger_squares(width, height),
+            Topology::Toroidal => square.next_larger_squares_toroidal(width, height),
+        }
+    }
+
+    fn smaller_squares(self, square: Square, width: u16, height: u16) -> Option<[Square; 4]> {
+        match self {
+            Topology::Bounded => square.next_smaller_squares(),
+            Topology::Toroidal => square.next_smaller_squares_toroidal(width, height),
+        }
+    }
+
+    fn bitmap(self, square: Square, width: u16, height: u16) -> Bitmap {
+        match self {
+            Topology::Bounded => square.bitmap(width, height),
+            Topology::Toroidal => square.bitmap_toroidal(width, height),
+        }
+    }
+
+    fn bitmap_into(self, square: Square, width: u16, height: u16, out: &mut Bitmap) {
+        match self {
+            Topology::Bounded => square.bitmap_into(width, height, out),
+            Topology::Toroidal => square.bitmap_toroidal_into(width, height, out),
+        }
+    }
+}
+
+/// Summary of a [`LookupTable`]'s shape and memory footprint
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LookupStats {
+    /// Number of squares indexed by the table, `width * height * size`
+    pub num_squares: usize,
+    /// Total number of entries in the 'next larger squares' table
+    pub larger_squares: usize,
+    /// Average number of larger squares per square
+    pub average_fanout: f64,
+    /// Approximate heap bytes used by the smaller, index and larger tables
+    pub table_bytes: usize,
+    /// Approximate heap bytes used by the precomputed bitmaps
+    pub bitmap_bytes: usize,
+}
+
 /// A lookup table for the 'next larger squares' operation
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LookupTable {
-    size: u16,
+    width: u16,
+    height: u16,
+    topology: Topology,
+    // Tables built by `new_capped` only hold squares up to this size, `None` when they go all
+    // the way to the grid size
+    #[cfg_attr(feature = "serde", serde(default))]
+    max_size: Option<u16>,
 
     // The shrinking table uses the sq_idx method to get the entry for a square
     smaller_squares: Vec<Option<[Square; 4]>>,
@@ -122,356 +460,4754 @@ pub struct LookupTable {
     // The growing table has arbitrary entry length, so we use a table to map sq_idx to the start of the entry
     // The end of the entry is the start of the next entry
     index: Vec<usize>,
-    larger_squares: Vec<Square>,
+    // Larger squares are stored as (dx, dy) offsets from the square they grow from, their size is
+    // always one more. See `LookupTable::compress`
+    larger_squares: Vec<(i8, i8)>,
 
     // Precompute the bitmap for each square, uses sq_idx
-    bitmaps: Vec<BitVec>,
+    #[cfg_attr(feature = "serde", serde(with = "bitmaps_serde"))]
+    bitmaps: Vec<Bitmap>,
+
+    // Lean tables don't store `bitmaps`, they are recomputed on demand instead
+    lean: bool,
 }
 
 impl LookupTable {
+    /// Builds the table for a `size` x `size` grid, in parallel when the `rayon` feature is
+    /// enabled
     pub fn new(size: u16) -> Self {
-        let gz = size as usize;
+        Self::new_rect(size, size)
+    }
+
+    /// Builds the table for a `width` x `height` grid, in parallel when the `rayon` feature is
+    /// enabled
+    pub fn new_rect(width: u16, height: u16) -> Self {
+        Self::with_topology(width, height, Topology::Bounded)
+    }
+
+    /// Builds the table for a `width` x `height` grid whose edges behave as `topology`
+    ///
+    /// On a [`Topology::Toroidal`] grid every cell can be the origin of a square of any size up
+    /// to `min(width, height)`, the square's cells wrap around the far edges.
+    pub fn with_topology(width: u16, height: u16, topology: Topology) -> Self {
+        let max_size = width.min(height);
+
+        #[cfg(feature = "rayon")]
+        return Self::new_parallel(width, height, topology, max_size);
+
+        #[cfg(not(feature = "rayon"))]
+        return Self::new_serial(width, height, topology, max_size, true, |_, _| {});
+    }
+
+    /// Builds the table for a `size` x `size` grid holding only the squares up to `max_size`
+    ///
+    /// Squares are indexed by size first, so this stores only the first `max_size` of the full
+    /// table's `size` layers. A [`PumpkinPatch`] using it never merges pumpkins past `max_size`, as if
+    /// [`PumpkinPatch::with_max_square_size`] was set. A cap of at least `size` builds the full
+    /// table.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_size` is 0
+    pub fn new_capped(size: u16, max_size: u16) -> Self {
+        assert!(max_size > 0, "pumpkins have to be at least 1x1");
+        Self::new_serial(
+            size,
+            size,
+            Topology::Bounded,
+            max_size.min(size),
+            true,
+            |_, _| {},
+        )
+    }
+
+    /// Builds a table without the precomputed bitmaps
+    ///
+    /// The bitmaps take `size^5` bits, which dominates the memory of a full table for large
+    /// grids. A lean table only stores the `size^3` sized adjacency tables, at the cost of
+    /// rebuilding a `size^2` bit bitmap every time [`PumpkinPatch::add`] visits a square.
+    pub fn new_lean(size: u16) -> Self {
+        Self::new_serial(size, size, Topology::Bounded, size, false, |_, _| {})
+    }
+
+    /// Builds the table for a `size` x `size` grid, calling `progress(completed, total)` as the
+    /// squares are filled in
+    ///
+    /// `progress` is called about every 1% of the squares and a last time with `completed ==
+    /// total`, cheap enough to redraw a progress bar. The table is always built on the current
+    /// thread, even with the `rayon` feature.
+    pub fn new_with_progress(size: u16, progress: impl FnMut(usize, usize)) -> Self {
+        Self::new_serial(size, size, Topology::Bounded, size, true, progress)
+    }
+
+    // Builds the entries of every square up to `max_size`, which is at most the grid size
+    fn new_serial(
+        width: u16,
+        height: u16,
+        topology: Topology,
+        max_size: u16,
+        store_bitmaps: bool,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Self {
+        let (w, h) = (width as usize, height as usize);
+        let num_squares = w * h * max_size as usize;
+        let report_every = (num_squares / 100).max(1);
 
-        let mut smaller_squares = vec![None; gz * gz * gz];
-        let mut index = vec![0; gz * gz * gz];
+        let mut smaller_squares = vec![None; num_squares];
+        let mut index = vec![0; num_squares];
         let mut larger_squares = Vec::new();
-        let mut bitmaps = vec![BitVec::new(); gz * gz * gz];
+        let mut bitmaps = if store_bitmaps {
+            vec![Bitmap::default(); num_squares]
+        } else {
+            Vec::new()
+        };
 
-        for idx in 0..gz * gz * gz {
-            let sq = Square::from_index(idx, gz);
+        for idx in 0..num_squares {
+            let sq = Square::from_index(idx, w, h);
 
             debug_assert!(sq.size.get() >= 1);
 
-            if let Some(squares) = sq.next_smaller_squares() {
+            if let Some(squares) = topology.smaller_squares(sq, width, height) {
                 smaller_squares[idx] = Some(squares);
             }
 
             let start = larger_squares.len();
-            larger_squares.extend(sq.next_larger_squares(gz as u16));
+            if sq.size() < max_size {
+                larger_squares.extend(
+                    topology
+                        .larger_squares(sq, width, height)
+                        .iter()
+                        .map(|larger| Self::compress(sq, larger, width, height)),
+                );
+            }
             index[idx] = start;
 
-            bitmaps[idx] = sq.bitmap(gz as u16);
+            if store_bitmaps {
+                topology.bitmap_into(sq, width, height, &mut bitmaps[idx]);
+            }
+
+            if (idx + 1) % report_every == 0 && idx + 1 < num_squares {
+                progress(idx + 1, num_squares);
+            }
         }
+        progress(num_squares, num_squares);
 
         Self {
-            size: gz as u16,
+            width,
+            height,
+            topology,
+            max_size: (max_size < width.min(height)).then_some(max_size),
             smaller_squares,
             index,
             larger_squares,
             bitmaps,
+            lean: !store_bitmaps,
         }
     }
 
-    fn get_larger(&self, square: Square) -> &[Square] {
-        let idx = square.idx(self.size as usize);
-        let start = self.index[idx];
-        let end = self
-            .index
-            .get(idx + 1)
-            .copied()
-            .unwrap_or(self.larger_squares.len());
+    // Each square's entries are computed independently, then the variable length larger square
+    // segments are concatenated in index order so the result matches `new_serial` exactly
+    #[cfg(feature = "rayon")]
+    fn new_parallel(width: u16, height: u16, topology: Topology, max_size: u16) -> Self {
+        use rayon::prelude::*;
 
-        &self.larger_squares[start..end]
-    }
+        let (w, h) = (width as usize, height as usize);
 
-    fn get_smaller(&self, square: Square) -> Option<&[Square; 4]> {
-        let idx = square.idx(self.size as usize);
-        self.smaller_squares[idx].as_ref()
-    }
+        let entries: Vec<_> = (0..w * h * max_size as usize)
+            .into_par_iter()
+            .map(|idx| {
+                let sq = Square::from_index(idx, w, h);
+                let larger = if sq.size() < max_size {
+                    topology.larger_squares(sq, width, height)
+                } else {
+                    Vec::new()
+                };
+                (
+                    topology.smaller_squares(sq, width, height),
+                    larger,
+                    topology.bitmap(sq, width, height),
+                )
+            })
+            .collect();
 
-    fn get_bitmap(&self, square: Square) -> BitVec {
-        self.bitmaps[square.idx(self.size as usize)].clone()
-    }
-}
+        let mut index = Vec::with_capacity(entries.len());
+        let mut total = 0;
+        for (_, larger, _) in &entries {
+            index.push(total);
+            total += larger.len();
+        }
 
-#[derive(Debug, Clone)]
-pub struct PumpkinPatch {
-    bitmap: BitVec,
-    ids: Vec<Option<NonZeroU16>>,
-    ids_transposed: Vec<Option<NonZeroU16>>,
-    size: u16,
-    lookup_table: Rc<LookupTable>,
-}
+        let mut smaller_squares = Vec::with_capacity(entries.len());
+        let mut larger_squares = Vec::with_capacity(total);
+        let mut bitmaps = Vec::with_capacity(entries.len());
+        for (idx, (smaller, larger, bitmap)) in entries.into_iter().enumerate() {
+            let sq = Square::from_index(idx, w, h);
+            smaller_squares.push(smaller);
+            larger_squares.extend(
+                larger
+                    .iter()
+                    .map(|larger| Self::compress(sq, larger, width, height)),
+            );
+            bitmaps.push(bitmap);
+        }
 
-impl PumpkinPatch {
-    pub fn new(size: u16, lookup_table: Rc<LookupTable>) -> Self {
-        let sz = size as usize;
         Self {
-            bitmap: BitVec::from_elem(sz * sz, false),
-            ids: vec![None; sz * sz],
-            ids_transposed: vec![None; sz * sz],
-            size,
-            lookup_table,
+            width,
+            height,
+            topology,
+            max_size: (max_size < width.min(height)).then_some(max_size),
+            smaller_squares,
+            index,
+            larger_squares,
+            bitmaps,
+            lean: false,
         }
     }
 
-    pub fn new_make_table(size: u16) -> Self {
-        Self::new(size, Rc::new(LookupTable::new(size)))
+    /// The largest square the table holds, the side length of the grid for square grids unless
+    /// the table was built with [`LookupTable::new_capped`]
+    pub fn size(&self) -> u16 {
+        self.max_size.unwrap_or(self.width.min(self.height))
     }
 
-    fn index(&self, x: u16, y: u16) -> usize {
-        (y * self.size + x) as usize
+    pub fn width(&self) -> u16 {
+        self.width
     }
 
-    pub fn get(&self, x: u16, y: u16) -> Option<NonZeroU16> {
-        self.ids[self.index(x, y)]
+    pub fn height(&self) -> u16 {
+        self.height
     }
 
-    pub fn contains(&self, x: u16, y: u16) -> bool {
-        self.bitmap[self.index(x, y)]
+    // Number of entries in the table, `Square::idx` is always below this
+    fn num_squares(&self) -> usize {
+        self.width as usize * self.height as usize * self.size() as usize
     }
 
-    fn check_boundary(&self, sq: &Square) -> bool {
-        #[cfg(debug_assertions)]
-        println!("Checking boundary for {:?}", sq);
+    pub fn topology(&self) -> Topology {
+        self.topology
+    }
 
-        // north is +y
-        if sq.y < self.size - sq.size.get() {
-            let inside_idx: usize = ((sq.y + sq.size.get() - 1) * self.size + sq.x) as usize;
-            let inside = &self.ids[inside_idx..inside_idx + sq.size.get() as usize];
-            let outside_idx: usize = ((sq.y + sq.size.get()) * self.size + sq.x) as usize;
-            let outside = &self.ids[outside_idx..outside_idx + sq.size.get() as usize];
+    /// Returns true if the table was built with [`LookupTable::new_lean`]
+    pub fn is_lean(&self) -> bool {
+        self.lean
+    }
 
-            #[cfg(debug_assertions)]
-            println!("NORTH inside: {:?}, outside: {:?}", inside, outside);
+    pub fn stats(&self) -> LookupStats {
+        use core::mem::{size_of, size_of_val};
 
-            if inside
+        let num_squares = self.index.len();
+        let table_bytes = self.smaller_squares.capacity() * size_of::<Option<[Square; 4]>>()
+            + self.index.capacity() * size_of::<usize>()
+            + self.larger_squares.capacity() * size_of::<(i8, i8)>();
+        let bitmap_bytes = self.bitmaps.capacity() * size_of::<Bitmap>()
+            + self
+                .bitmaps
                 .iter()
-                .zip(outside.iter())
-                .any(|(a, b)| b.is_some() && a == b)
-            {
-                return false;
-            }
-        }
+                .map(|bitmap| size_of_val(bitmap.words()))
+                .sum::<usize>();
 
-        // south is -y
-        if sq.y > 0 {
-            let inside_idx = (sq.y * self.size + sq.x) as usize;
-            let inside = &self.ids[inside_idx..inside_idx + sq.size.get() as usize];
-            let outside_idx = ((sq.y - 1) * self.size + sq.x) as usize;
-            let outside = &self.ids[outside_idx..outside_idx + sq.size.get() as usize];
-
-            #[cfg(debug_assertions)]
-            println!("SOUTH inside: {:?}, outside: {:?}", inside, outside);
-
-            if inside
-                .iter()
-                .zip(outside.iter())
-                .any(|(a, b)| b.is_some() && a == b)
-            {
-                return false;
-            }
+        LookupStats {
+            num_squares,
+            larger_squares: self.larger_squares.len(),
+            average_fanout: if num_squares == 0 {
+                0.0
+            } else {
+                self.larger_squares.len() as f64 / num_squares as f64
+            },
+            table_bytes,
+            bitmap_bytes,
         }
+    }
 
-        // east is +x
-        // uses the transposed ids
-        if sq.x < self.size - sq.size.get() {
-            let inside_idx = ((sq.x + sq.size.get() - 1) * self.size + sq.y) as usize;
-            let inside = &self.ids_transposed[inside_idx..inside_idx + sq.size.get() as usize];
-            let outside_idx = ((sq.x + sq.size.get()) * self.size + sq.y) as usize;
-            let outside = &self.ids_transposed[outside_idx..outside_idx + sq.size.get() as usize];
-
-            #[cfg(debug_assertions)]
-            println!("EAST inside: {:?}, outside: {:?}", inside, outside);
-
-            if inside
-                .iter()
-                .zip(outside.iter())
-                .any(|(a, b)| b.is_some() && a == b)
-            {
-                return false;
-            }
+    /// Maps each fan-out, the number of larger squares of a square, to how many squares have it
+    ///
+    /// Every entry of the table is counted, so the counts add up to `num_squares` of
+    /// [`LookupTable::stats`].
+    pub fn fanout_histogram(&self) -> BTreeMap<usize, usize> {
+        // each entry ends where the next one starts
+        let ends = self.index.iter().skip(1).copied();
+        let mut histogram = BTreeMap::new();
+        for (start, end) in self
+            .index
+            .iter()
+            .zip(ends.chain([self.larger_squares.len()]))
+        {
+            *histogram.entry(end - start).or_insert(0) += 1;
         }
+        histogram
+    }
 
-        // west is -x
-        // uses the transposed ids
-        if sq.x > 0 {
-            let inside_idx = (sq.x * self.size + sq.y) as usize;
-            let inside = &self.ids_transposed[inside_idx..inside_idx + sq.size.get() as usize];
-            let outside_idx = ((sq.x - 1) * self.size + sq.y) as usize;
-            let outside = &self.ids_transposed[outside_idx..outside_idx + sq.size.get() as usize];
+    /// Draws the containment lattice as a GraphViz digraph
+    ///
+    /// Every square of size up to `max_size` is a node labeled `x,y,size`, with an edge to each
+    /// of its larger squares that is also at most `max_size`.
+    pub fn to_dot(&self, max_size: u16) -> String {
+        use core::fmt::Write;
 
-            #[cfg(debug_assertions)]
-            println!("WEST inside: {:?}, outside: {:?}", inside, outside);
+        let label = |sq: Square| format!("\"{},{},{}\"", sq.x, sq.y, sq.size());
+        let squares = (0..self.num_squares())
+            .map(|idx| Square::from_index(idx, self.width as usize, self.height as usize))
+            .filter(|&sq| sq.size() <= max_size && self.holds(sq));
 
-            if inside
-                .iter()
-                .zip(outside.iter())
-                .any(|(a, b)| b.is_some() && a == b)
-            {
-                return false;
+        let mut dot = String::from("digraph squares {\n");
+        for sq in squares {
+            writeln!(dot, "    {};", label(sq)).unwrap();
+            for larger in self.get_larger(sq).filter(|l| l.size() <= max_size) {
+                writeln!(dot, "    {} -> {};", label(sq), label(larger)).unwrap();
             }
         }
+        dot.push_str("}\n");
 
-        true
+        dot
     }
 
-    /// DFS algorithm to fund the largest square containing (x, y) that can be merged into a bigger pumpkin
-    pub fn add(&mut self, x: u16, y: u16) -> Square {
-        debug_assert!(!self.contains(x, y));
-        self.bitmap.set(self.index(x, y), true);
+    /// Writes the table to `path` so it can be reused with [`LookupTable::load`]
+    #[cfg(feature = "bincode")]
+    pub fn save(&self, path: &std::path::Path) -> std::io::Result<()> {
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        bincode::serialize_into(&mut writer, self)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        std::io::Write::flush(&mut writer)
+    }
 
-        let start = Square::new(x, y, 1);
-        let mut largest_square = start;
+    /// Reads a table written by [`LookupTable::save`], rejecting files whose contents don't
+    /// pass [`LookupTable::validate`]
+    #[cfg(feature = "bincode")]
+    pub fn load(path: &std::path::Path) -> std::io::Result<LookupTable> {
+        let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+        let table: LookupTable = bincode::deserialize_from(reader)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        table
+            .validate()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok(table)
+    }
 
-        let sz = self.size as usize;
-        let mut visited = BitVec::from_elem(sz * sz * sz, false);
-        visited.set(start.idx(sz), true);
-        let mut stack = vec![start];
+    /// Checks the table's internal invariants, describing the first violation found
+    pub fn validate(&self) -> Result<(), String> {
+        self.check_lengths()?;
 
-        while let Some(square) = stack.pop() {
-            debug_assert_eq!(
-                self.lookup_table.get_bitmap(square),
-                square.bitmap(self.size)
-            );
+        let (w, h) = (self.width as usize, self.height as usize);
 
-            if !self.lookup_table.get_bitmap(square).and(&self.bitmap) {
-                let neighbors: Vec<Square> = self
-                    .lookup_table
-                    .get_larger(square)
-                    .iter()
-                    .filter(|sq| !visited.get(sq.idx(sz)).unwrap())
-                    .cloned()
-                    .collect();
+        if let Some(&first) = self.index.first() {
+            if first != 0 {
+                return Err(format!("index starts at {}, expected 0", first));
+            }
+        }
+        if let Some(i) = self.index.windows(2).position(|w| w[0] > w[1]) {
+            return Err(format!(
+                "index decreases from {} to {} at {}",
+                self.index[i],
+                self.index[i + 1],
+                i + 1
+            ));
+        }
 
-                for sq in &neighbors {
-                    visited.set(sq.idx(sz), true);
+        for idx in 0..self.num_squares() {
+            let sq = Square::from_index(idx, w, h);
+
+            for larger in self.get_larger_unchecked(idx, sq) {
+                if larger.size() != sq.size() + 1 || !self.encloses(&larger, &sq) {
+                    return Err(format!("{:?} is not a larger square of {:?}", larger, sq));
                 }
-                stack.extend(neighbors);
+            }
 
-                if square.size > largest_square.size && self.check_boundary(&square) {
-                    largest_square = square;
+            match &self.smaller_squares[idx] {
+                Some(smaller) => {
+                    if let Some(bad) = smaller
+                        .iter()
+                        .find(|s| s.size() + 1 != sq.size() || !self.encloses(&sq, s))
+                    {
+                        return Err(format!("{:?} is not a smaller square of {:?}", bad, sq));
+                    }
+                }
+                None if sq.size() > 1 => {
+                    return Err(format!("{:?} is missing its smaller squares", sq));
                 }
+                None => {}
             }
-        }
 
-        // Fill the bitmap and ids with the new square
-        let id = NonZeroU16::new(largest_square.y * self.size + largest_square.x + 1);
-        for y in largest_square.y..largest_square.y + largest_square.size.get() {
-            for x in largest_square.x..largest_square.x + largest_square.size.get() {
-                let idx = (y * self.size + x) as usize;
-                let idx_t = (x * self.size + y) as usize;
-                self.ids[idx] = id;
-                self.ids_transposed[idx_t] = id;
+            if !self.lean && self.bitmaps[idx] != self.square_bitmap(sq) {
+                return Err(format!("bitmap of {:?} doesn't match the square", sq));
             }
         }
 
-        largest_square
+        Ok(())
     }
-}
 
-impl std::fmt::Display for PumpkinPatch {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // Print the ids in a grid, but reverse the order of the y direction
-        for y in (0..self.size).rev() {
-            for x in 0..self.size {
-                let id = self.ids[self.index(x, y)].map_or(0, |id| id.get());
-                write!(f, "{:3} ", id)?;
+    // Returns true if every cell of `inner` is inside `outer`, taking wrapping into account
+    fn encloses(&self, outer: &Square, inner: &Square) -> bool {
+        match self.topology {
+            Topology::Bounded => outer.contains_square(inner),
+            Topology::Toroidal => self
+                .square_bitmap(*inner)
+                .is_subset(&self.square_bitmap(*outer)),
+        }
+    }
+
+    // Checks that the internal vectors have the lengths implied by `width` and `height`
+    fn check_lengths(&self) -> Result<(), String> {
+        let cells = self.width as usize * self.height as usize;
+        if let Some(max_size) = self.max_size {
+            if max_size == 0 || max_size >= self.width.min(self.height) {
+                return Err(format!(
+                    "max size {} isn't below the grid size {}",
+                    max_size,
+                    self.width.min(self.height)
+                ));
             }
-            writeln!(f)?;
+        }
+        let num_squares = self.num_squares();
+
+        if self.smaller_squares.len() != num_squares {
+            return Err(format!(
+                "expected {} smaller square entries, found {}",
+                num_squares,
+                self.smaller_squares.len()
+            ));
+        }
+        if self.index.len() != num_squares {
+            return Err(format!(
+                "expected {} index entries, found {}",
+                num_squares,
+                self.index.len()
+            ));
+        }
+        if self
+            .index
+            .last()
+            .is_some_and(|&i| i > self.larger_squares.len())
+        {
+            return Err("index points past the end of the larger squares".to_string());
+        }
+
+        let expected_bitmaps = if self.lean { 0 } else { num_squares };
+        if self.bitmaps.len() != expected_bitmaps {
+            return Err(format!(
+                "expected {} bitmaps, found {}",
+                expected_bitmaps,
+                self.bitmaps.len()
+            ));
+        }
+        if let Some(bitmap) = self.bitmaps.iter().find(|b| b.len() != cells) {
+            return Err(format!(
+                "expected bitmaps of {} bits, found {}",
+                cells,
+                bitmap.len()
+            ));
         }
 
         Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rand::seq::SliceRandom;
 
-    #[test]
-    fn test_bit_sq() {
-        let bit_sq = Square::new(0, 0, 3);
-        // (1, 1) and (2, 2) should be contained
-        assert!(bit_sq.contains(1, 1), "bit_sq should contain (1, 1)");
-        assert!(bit_sq.contains(2, 2), "bit_sq should contain (2, 2)");
-        // (0, 3) should not be contained
-        assert!(!bit_sq.contains(0, 3), "bit_sq should not contain (0, 3)");
-        // (2, 1)
-        assert!(bit_sq.contains(2, 1), "bit_sq should contain (2, 1)");
+    /// The squares one size larger that contain `square`
+    pub fn get_larger(&self, square: Square) -> impl ExactSizeIterator<Item = Square> + '_ {
+        debug_assert!(self.holds(square));
 
-        // Try another square
-        let bit_sq = Square::new(5, 5, 5);
-        // (5, 5) and (9, 9) should be contained
-        assert!(bit_sq.contains(5, 5), "bit_sq should contain (5, 5)");
-        assert!(bit_sq.contains(9, 9), "bit_sq should contain (9, 9)");
+        self.get_larger_unchecked(self.idx(square), square)
     }
 
-    #[test]
-    fn test_pumpkins_merge_2() {
-        let mut pumpkins = PumpkinPatch::new_make_table(2);
-        let order: &[(u16, u16)] = &[(0, 0), (0, 1), (1, 0), (1, 1)];
-
+    fn get_larger_unchecked(
+        &self,
+        idx: usize,
+        square: Square,
+    ) -> impl ExactSizeIterator<Item = Square> + '_ {
+        let start = self.index[idx];
+        let end = self
+            .index
+            .get(idx + 1)
+            .copied()
+            .unwrap_or(self.larger_squares.len());
+
+        self.larger_squares[start..end]
+            .iter()
+            .map(move |&offset| self.decompress(square, offset))
+    }
+
+    // Larger squares never start more than one cell below or left of the square they contain, so
+    // the offset always fits in an i8. On a torus the step left from column 0 lands on the far
+    // column, it's stored as -1 all the same.
+    fn compress(square: Square, larger: &Square, width: u16, height: u16) -> (i8, i8) {
+        let offset = |from: u16, to: u16, n: u16| {
+            let d = to as i32 - from as i32;
+            if d > 0 {
+                d - n as i32
+            } else {
+                d
+            }
+        };
+        let dx = offset(square.x, larger.x, width);
+        let dy = offset(square.y, larger.y, height);
+        debug_assert!(i8::try_from(dx).is_ok() && i8::try_from(dy).is_ok());
+
+        (dx as i8, dy as i8)
+    }
+
+    fn decompress(&self, square: Square, (dx, dy): (i8, i8)) -> Square {
+        Square::new(
+            wrap(square.x as i32 + dx as i32, self.width),
+            wrap(square.y as i32 + dy as i32, self.height),
+            square.size() + 1,
+        )
+    }
+
+    /// The four squares one size smaller contained by `square`, `None` for size 1 squares
+    pub fn get_smaller(&self, square: Square) -> Option<&[Square; 4]> {
+        debug_assert!(self.holds(square));
+
+        self.smaller_squares[self.idx(square)].as_ref()
+    }
+
+    // Precomputed tables hand out a borrow, lean tables have to build the bitmap
+    fn get_bitmap(&self, square: Square) -> Cow<'_, Bitmap> {
+        if self.lean {
+            Cow::Owned(self.square_bitmap(square))
+        } else {
+            Cow::Borrowed(&self.bitmaps[self.idx(square)])
+        }
+    }
+
+    // Builds the bitmap of `square` from scratch
+    fn square_bitmap(&self, square: Square) -> Bitmap {
+        self.topology.bitmap(square, self.width, self.height)
+    }
+
+    // Range of bit indexes that holds every cell of `square`. Bounded squares only span the rows
+    // they cover, wrapping squares can touch both ends of the grid.
+    fn bit_span(&self, square: Square) -> Range<usize> {
+        match self.topology {
+            Topology::Bounded => {
+                let (width, size) = (self.width as usize, square.size() as usize);
+                let start = square.y as usize * width + square.x as usize;
+                start..start + (size - 1) * width + size
+            }
+            Topology::Toroidal => 0..self.width as usize * self.height as usize,
+        }
+    }
+
+    // Returns true if the table has an entry for `square`. Squares on a bounded grid have to fit
+    // inside it, on a torus any origin works.
+    fn holds(&self, square: Square) -> bool {
+        match self.topology {
+            Topology::Bounded => {
+                square.fits(self.width, self.height) && square.size() <= self.size()
+            }
+            Topology::Toroidal => {
+                square.x < self.width && square.y < self.height && square.size() <= self.size()
+            }
+        }
+    }
+
+    fn idx(&self, square: Square) -> usize {
+        square.idx(self.width as usize, self.height as usize)
+    }
+}
+
+// Serializes a bitmap as its bit length followed by its packed bytes
+#[cfg(feature = "serde")]
+mod bitmap_serde {
+    use crate::bitmap::Bitmap;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(bitmap: &Bitmap, serializer: S) -> Result<S::Ok, S::Error> {
+        (bitmap.len(), bitmap.to_bytes()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bitmap, D::Error> {
+        let (len, bytes) = <(usize, Vec<u8>)>::deserialize(deserializer)?;
+        from_raw(len, &bytes).map_err(D::Error::custom)
+    }
+
+    pub(super) fn from_raw(len: usize, bytes: &[u8]) -> Result<Bitmap, &'static str> {
+        Bitmap::from_bytes(len, bytes).ok_or("bitmap length doesn't match its data")
+    }
+}
+
+// Serializes each bitmap the same way as `bitmap_serde`
+#[cfg(feature = "serde")]
+mod bitmaps_serde {
+    use crate::bitmap::Bitmap;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bitmaps: &[Bitmap], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            bitmaps
+                .iter()
+                .map(|bitmap| (bitmap.len(), bitmap.to_bytes())),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Bitmap>, D::Error> {
+        let raw = Vec::<(usize, Vec<u8>)>::deserialize(deserializer)?;
+        raw.into_iter()
+            .map(|(len, bytes)| {
+                super::bitmap_serde::from_raw(len, &bytes).map_err(D::Error::custom)
+            })
+            .collect()
+    }
+}
+
+/// Which square [`PumpkinPatch::add`] merges into when several of the largest size are possible
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TieBreak {
+    /// The first one the search reaches, which depends on the order of the lookup table
+    #[default]
+    FirstFound,
+    /// The one with the smallest origin, comparing x first
+    LowestXY,
+    /// The one with the largest origin, comparing x first
+    HighestXY,
+}
+
+impl TieBreak {
+    // Returns true if `candidate` should replace `current`, a square of the same size
+    fn prefers(self, candidate: Square, current: Square) -> bool {
+        match self {
+            TieBreak::FirstFound => false,
+            TieBreak::LowestXY => (candidate.x, candidate.y) < (current.x, current.y),
+            TieBreak::HighestXY => (candidate.x, candidate.y) > (current.x, current.y),
+        }
+    }
+}
+
+/// Reasons [`PumpkinPatch::add`] can reject a cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddError {
+    /// The cell already holds a pumpkin
+    AlreadyFilled,
+    /// The cell is outside of the grid
+    OutOfBounds,
+    /// The cell was filled, but no pumpkin of the patch's minimum size can form yet. It stays
+    /// unassigned until a later `add` merges it into a large enough square.
+    Deferred,
+}
+
+impl core::fmt::Display for AddError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AddError::AlreadyFilled => write!(f, "cell is already filled"),
+            AddError::OutOfBounds => write!(f, "cell is outside of the grid"),
+            AddError::Deferred => write!(f, "cell was filled but can't form a pumpkin yet"),
+        }
+    }
+}
+
+impl core::error::Error for AddError {}
+
+/// A merge reported by [`PumpkinPatch::add_with_hook`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeEvent {
+    /// The pumpkin the added cell merged into
+    pub square: Square,
+    /// Ids of the pumpkins that were inside `square` before the merge, in increasing order
+    pub absorbed: Vec<NonZeroU32>,
+}
+
+/// How much work the merge search did for one insert, see [`PumpkinPatch::add_with_stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AddStats {
+    /// Squares taken off the search stack, including the ones that weren't fully filled
+    pub squares_visited: usize,
+    /// Squares that had their boundary checked, the ones larger than the best so far and the
+    /// equally large ones the [`TieBreak`] prefers
+    pub boundary_checks: usize,
+    /// Size of the largest square whose other cells were all filled, whether or not it passed
+    /// the boundary check
+    pub max_square_tried: u16,
+}
+
+/// A cell whose id differs between two patches, see [`PumpkinPatch::diff`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellChange {
+    pub x: u16,
+    pub y: u16,
+    pub from: Option<NonZeroU32>,
+    pub to: Option<NonZeroU32>,
+}
+
+/// Reasons [`PumpkinPatch::from_mask_str`], [`PumpkinPatch::from_bool_grid`] and
+/// [`PumpkinPatch::from_rle`] can reject their input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The mask has no rows
+    Empty,
+    /// A row's length differs from the first row's
+    Ragged {
+        row: usize,
+        expected: usize,
+        found: usize,
+    },
+    /// A cell is neither `#` nor `.`
+    InvalidChar { row: usize, col: usize, found: char },
+    /// The lookup table was built for a `width` x `height` grid of a different size
+    TableMismatch { width: u16, height: u16 },
+    /// Run `run` of an RLE string isn't a `count:id` pair
+    InvalidRun { run: usize },
+    /// The runs of an RLE string cover `found` cells instead of `expected`
+    CellCount { expected: usize, found: usize },
+    /// The decoded ids don't form square pumpkins
+    InvalidIds,
+}
+
+impl core::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "mask is empty"),
+            ParseError::Ragged {
+                row,
+                expected,
+                found,
+            } => write!(f, "row {} has {} cells, expected {}", row, found, expected),
+            ParseError::InvalidChar { row, col, found } => write!(
+                f,
+                "unexpected {:?} at row {} column {}, expected '#' or '.'",
+                found, row, col
+            ),
+            ParseError::TableMismatch { width, height } => write!(
+                f,
+                "mask doesn't match the {}x{} lookup table",
+                width, height
+            ),
+            ParseError::InvalidRun { run } => {
+                write!(f, "run {} isn't a count:id pair", run)
+            }
+            ParseError::CellCount { expected, found } => {
+                write!(f, "runs cover {} cells, expected {}", found, expected)
+            }
+            ParseError::InvalidIds => write!(f, "ids don't form square pumpkins"),
+        }
+    }
+}
+
+impl core::error::Error for ParseError {}
+
+/// Reasons [`PumpkinPatchBuilder::build`] can reject a configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    /// Neither a size nor a lookup table was given
+    MissingSize,
+    /// The grid has no cells
+    EmptyGrid,
+    /// The lookup table was built for a `width` x `height` grid of a different size
+    TableMismatch { width: u16, height: u16 },
+    /// The lookup table was built for a different topology
+    TopologyMismatch { table: Topology },
+    /// A square size limit is 0
+    ZeroSquareSize,
+    /// The minimum square size is larger than the maximum
+    MinAboveMax { min: u16, max: u16 },
+}
+
+impl core::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BuildError::MissingSize => write!(f, "either a size or a lookup table is needed"),
+            BuildError::EmptyGrid => write!(f, "the grid has no cells"),
+            BuildError::TableMismatch { width, height } => write!(
+                f,
+                "grid doesn't match the {}x{} lookup table",
+                width, height
+            ),
+            BuildError::TopologyMismatch { table } => {
+                write!(f, "the lookup table is {:?}", table)
+            }
+            BuildError::ZeroSquareSize => write!(f, "pumpkins have to be at least 1x1"),
+            BuildError::MinAboveMax { min, max } => {
+                write!(f, "min square size {} is larger than the max {}", min, max)
+            }
+        }
+    }
+}
+
+impl core::error::Error for BuildError {}
+
+/// A grid of pumpkins sharing a [`LookupTable`] through `P`
+///
+/// `P` is `Rc<LookupTable>` by default, use `Arc<LookupTable>` to share one table between
+/// patches living on different threads.
+#[derive(Debug, Clone)]
+pub struct PumpkinPatch<P = Rc<LookupTable>> {
+    bitmap: Bitmap,
+    // `None` takes the niche of `NonZeroU32`, so this is already as packed as a `Vec<u32>` with
+    // 0 for empty cells
+    ids: Vec<Option<NonZeroU32>>,
+    width: u16,
+    height: u16,
+    lookup_table: P,
+
+    // Number of set bits in `bitmap`
+    filled: usize,
+
+    // Pumpkins never merge into squares larger than this
+    max_square_size: Option<u16>,
+    // Filled cells are left without an id until they can form a square at least this large
+    min_square_size: Option<u16>,
+    // Picks among equally large squares
+    tie_break: TieBreak,
+
+    // Insertion order ids, only kept once `with_sequential_ids` is called
+    sequence: Option<Sequence>,
+
+    // What every `add` since the last `remove` or `clear` changed, only kept once `with_undo`
+    // is called
+    history: Option<History>,
+
+    // Buffers reused by every `add` so the search doesn't allocate
+    search: Search,
+}
+
+// Sequential ids of the pumpkins, see `PumpkinPatch::with_sequential_ids`
+#[derive(Debug, Clone, Default)]
+struct Sequence {
+    // Origin id of every pumpkin in the patch to its sequential id
+    by_origin: BTreeMap<NonZeroU32, NonZeroU32>,
+    // Sequential id - 1 to origin id of every pumpkin ever created, the pumpkin is still in the
+    // patch if `by_origin` maps its origin back to the same sequential id
+    origins: Vec<NonZeroU32>,
+}
+
+impl Sequence {
+    fn push(&mut self, id: NonZeroU32) {
+        self.origins.push(id);
+        let seq = NonZeroU32::new(self.origins.len() as u32).unwrap();
+        self.by_origin.insert(id, seq);
+    }
+
+    fn retire(&mut self, id: NonZeroU32) {
+        self.by_origin.remove(&id);
+    }
+
+    // Reverts the last `push`
+    fn pop(&mut self) {
+        if let Some(id) = self.origins.pop() {
+            self.by_origin.remove(&id);
+        }
+    }
+
+    // Brings back the pumpkin with origin `id` retired by the last `push`. It was the last one
+    // created at that origin, any later one would have absorbed it.
+    fn restore(&mut self, id: NonZeroU32) {
+        if let Some(idx) = self.origins.iter().rposition(|&origin| origin == id) {
+            self.by_origin
+                .insert(id, NonZeroU32::new(idx as u32 + 1).unwrap());
+        }
+    }
+}
+
+// Undo stack of `PumpkinPatch`. The squares absorbed by every insert are kept in one stack so
+// recording an `add` doesn't allocate once the buffers have grown.
+#[derive(Debug, Clone, Default)]
+struct History {
+    inserts: Vec<Insert>,
+    absorbed: Vec<Square>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Insert {
+    x: u16,
+    y: u16,
+    // The pumpkin the insert created, `None` if the cell was deferred
+    square: Option<Square>,
+    // Number of squares at the top of `History::absorbed` that were pumpkins before the insert
+    absorbed: usize,
+}
+
+impl History {
+    fn clear(&mut self) {
+        self.inserts.clear();
+        self.absorbed.clear();
+    }
+}
+
+// Working memory of `PumpkinPatch::largest_square`
+#[derive(Debug, Clone, Default)]
+struct Search {
+    // One bit per square in the lookup table, only the bits listed in `touched` are ever set
+    visited: Bitmap,
+    touched: Vec<usize>,
+    stack: Vec<Square>,
+}
+
+impl Search {
+    // Sizes the buffers for searches on `table` up front. The stack rarely holds more than a
+    // few squares per size, while `touched` grows with the area of the grid once it fills up.
+    // Both still grow if a search needs more.
+    fn new(table: &LookupTable) -> Self {
+        let size = table.size() as usize;
+        Self {
+            visited: Bitmap::new(table.num_squares()),
+            touched: Vec::with_capacity(size * size),
+            stack: Vec::with_capacity(4 * size),
+        }
+    }
+}
+
+/// The cells and pumpkins of a [`PumpkinPatch`] saved by [`PumpkinPatch::snapshot`]
+#[derive(Debug, Clone)]
+pub struct PatchSnapshot {
+    width: u16,
+    height: u16,
+    bitmap: Bitmap,
+    ids: Vec<Option<NonZeroU32>>,
+    filled: usize,
+    sequence: Option<Sequence>,
+}
+
+/// Collects the options of a [`PumpkinPatch`] and checks they fit together before creating it
+///
+/// Without a lookup table `build` makes one for the grid. With one, the grid defaults to the
+/// table's dimensions and topology.
+#[derive(Debug, Clone)]
+pub struct PumpkinPatchBuilder<P = Rc<LookupTable>> {
+    dimensions: Option<(u16, u16)>,
+    lookup_table: Option<P>,
+    topology: Option<Topology>,
+    max_square_size: Option<u16>,
+    min_square_size: Option<u16>,
+    tie_break: TieBreak,
+    sequential_ids: bool,
+    undo: bool,
+}
+
+impl<P> Default for PumpkinPatchBuilder<P> {
+    fn default() -> Self {
+        Self {
+            dimensions: None,
+            lookup_table: None,
+            topology: None,
+            max_square_size: None,
+            min_square_size: None,
+            tie_break: TieBreak::default(),
+            sequential_ids: false,
+            undo: false,
+        }
+    }
+}
+
+impl<P: Deref<Target = LookupTable> + From<LookupTable>> PumpkinPatchBuilder<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A `size` x `size` grid
+    pub fn size(self, size: u16) -> Self {
+        self.rect(size, size)
+    }
+
+    /// A `width` x `height` grid
+    pub fn rect(mut self, width: u16, height: u16) -> Self {
+        self.dimensions = Some((width, height));
+        self
+    }
+
+    /// Shares an existing table instead of building one
+    pub fn lookup_table(mut self, lookup_table: P) -> Self {
+        self.lookup_table = Some(lookup_table);
+        self
+    }
+
+    pub fn topology(mut self, topology: Topology) -> Self {
+        self.topology = Some(topology);
+        self
+    }
+
+    /// See [`PumpkinPatch::with_max_square_size`]
+    pub fn max_square_size(mut self, max: u16) -> Self {
+        self.max_square_size = Some(max);
+        self
+    }
+
+    /// See [`PumpkinPatch::with_min_square_size`]
+    pub fn min_square_size(mut self, min: u16) -> Self {
+        self.min_square_size = Some(min);
+        self
+    }
+
+    /// See [`PumpkinPatch::with_tie_break`]
+    pub fn tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+
+    /// See [`PumpkinPatch::with_sequential_ids`]
+    pub fn sequential_ids(mut self) -> Self {
+        self.sequential_ids = true;
+        self
+    }
+
+    /// See [`PumpkinPatch::with_undo`]
+    pub fn undo(mut self) -> Self {
+        self.undo = true;
+        self
+    }
+
+    pub fn build(self) -> Result<PumpkinPatch<P>, BuildError> {
+        if self.max_square_size == Some(0) || self.min_square_size == Some(0) {
+            return Err(BuildError::ZeroSquareSize);
+        }
+        if let (Some(min), Some(max)) = (self.min_square_size, self.max_square_size) {
+            if min > max {
+                return Err(BuildError::MinAboveMax { min, max });
+            }
+        }
+
+        let (width, height) = match (self.dimensions, &self.lookup_table) {
+            (Some(dimensions), _) => dimensions,
+            (None, Some(table)) => (table.width(), table.height()),
+            (None, None) => return Err(BuildError::MissingSize),
+        };
+        if width == 0 || height == 0 {
+            return Err(BuildError::EmptyGrid);
+        }
+
+        let lookup_table = match self.lookup_table {
+            Some(table) => {
+                if (table.width(), table.height()) != (width, height) {
+                    return Err(BuildError::TableMismatch {
+                        width: table.width(),
+                        height: table.height(),
+                    });
+                }
+                if self.topology.is_some_and(|t| t != table.topology()) {
+                    return Err(BuildError::TopologyMismatch {
+                        table: table.topology(),
+                    });
+                }
+                table
+            }
+            None => P::from(LookupTable::with_topology(
+                width,
+                height,
+                self.topology.unwrap_or_default(),
+            )),
+        };
+
+        let mut patch = PumpkinPatch::new_rect(width, height, lookup_table);
+        patch.max_square_size = self.max_square_size;
+        patch.min_square_size = self.min_square_size;
+        patch.tie_break = self.tie_break;
+        if self.sequential_ids {
+            patch = patch.with_sequential_ids();
+        }
+        if self.undo {
+            patch = patch.with_undo();
+        }
+        Ok(patch)
+    }
+}
+
+impl PumpkinPatch {
+    /// Starts configuring a patch, see [`PumpkinPatchBuilder`]
+    pub fn builder() -> PumpkinPatchBuilder {
+        PumpkinPatchBuilder::new()
+    }
+
+    pub fn new_make_table(size: u16) -> Self {
+        Self::new(size, Rc::new(LookupTable::new(size)))
+    }
+
+    /// Adds the cells of `order` to an empty `size` x `size` patch, writing the patch after
+    /// every insert as a frame of an animated GIF
+    ///
+    /// Frames are drawn like [`PumpkinPatch::render_png`], so a merge recolors the whole new
+    /// pumpkin in the frame of the insert that caused it. Cells that can't be added still get a
+    /// frame, unchanged from the one before.
+    #[cfg(feature = "image")]
+    pub fn record_gif(
+        size: u16,
+        order: &[(u16, u16)],
+        path: &std::path::Path,
+        cell_px: u32,
+        ms_per_frame: u16,
+    ) -> image::ImageResult<()> {
+        use image::codecs::gif::{GifEncoder, Repeat};
+
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let mut encoder = GifEncoder::new(file);
+        encoder.set_repeat(Repeat::Infinite)?;
+
+        let delay = image::Delay::from_numer_denom_ms(ms_per_frame as u32, 1);
+        let mut patch = Self::new_make_table(size);
+        for &(x, y) in order {
+            patch.add(x, y).ok();
+            let frame = image::DynamicImage::from(patch.render_image(cell_px)).into_rgba8();
+            encoder.encode_frame(image::Frame::from_parts(frame, 0, 0, delay))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<P: Deref<Target = LookupTable>> PumpkinPatch<P> {
+    pub fn new(size: u16, lookup_table: P) -> Self {
+        Self::new_rect(size, size, lookup_table)
+    }
+
+    /// Creates an empty `width` x `height` patch, pumpkins are still squares
+    pub fn new_rect(width: u16, height: u16, lookup_table: P) -> Self {
+        debug_assert_eq!(
+            (lookup_table.width(), lookup_table.height()),
+            (width, height)
+        );
+
+        let cells = width as usize * height as usize;
+        let search = Search::new(&lookup_table);
+        Self {
+            bitmap: Bitmap::new(cells),
+            ids: vec![None; cells],
+            width,
+            height,
+            lookup_table,
+            filled: 0,
+            max_square_size: None,
+            min_square_size: None,
+            tie_break: TieBreak::default(),
+            sequence: None,
+            history: None,
+            search,
+        }
+    }
+
+    /// Caps the size of the pumpkins `add` merges into at `max`, however much room there is
+    ///
+    /// Filling a 4x4 patch capped at 2 ends with four size-2 pumpkins instead of one size-4.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is 0 or smaller than the minimum size
+    pub fn with_max_square_size(mut self, max: u16) -> Self {
+        assert!(max > 0, "pumpkins have to be at least 1x1");
+        assert!(
+            self.min_square_size.is_none_or(|min| min <= max),
+            "the max square size is smaller than the min"
+        );
+        self.max_square_size = Some(max);
+        self
+    }
+
+    /// Leaves added cells without a pumpkin until they can merge into a square of at least
+    /// `min`
+    ///
+    /// `add` still fills the cell but returns [`AddError::Deferred`] when the square it would
+    /// form is too small. A later `add` that completes a large enough square assigns every cell
+    /// inside it, including the deferred ones.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min` is 0 or larger than the maximum size
+    pub fn with_min_square_size(mut self, min: u16) -> Self {
+        assert!(min > 0, "pumpkins have to be at least 1x1");
+        assert!(
+            self.max_square_size.is_none_or(|max| min <= max),
+            "the min square size is larger than the max"
+        );
+        self.min_square_size = Some(min);
+        self
+    }
+
+    /// Chooses which square `add` merges into when several of the largest possible size don't
+    /// cross a pumpkin
+    pub fn with_tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+
+    /// Also numbers the pumpkins 1, 2, 3, ... in the order they are created
+    ///
+    /// The regular ids encode each pumpkin's origin, so they are spread over `1..=width *
+    /// height`. Sequential ids are small and dense enough to index a palette or an array. Each
+    /// merge creates a new pumpkin with the next id, the ids of the pumpkins it absorbed aren't
+    /// handed out again. Pumpkins already in the patch are numbered in row-major order of their
+    /// origins. Sequential ids aren't serialized.
+    pub fn with_sequential_ids(mut self) -> Self {
+        let mut sequence = Sequence::default();
+        for (id, _) in self.pumpkins() {
+            sequence.push(id);
+        }
+        self.sequence = Some(sequence);
+        // undoing an earlier insert would have to know its sequential ids
+        self.clear_history();
+        self
+    }
+
+    /// Keeps a history of the `add`s so they can be reverted with [`PumpkinPatch::undo`]
+    ///
+    /// The history grows with every `add` until a `remove` or `clear` forgets it, patches that
+    /// are never undone are better off without it.
+    pub fn with_undo(mut self) -> Self {
+        self.history.get_or_insert_with(History::default);
+        self
+    }
+
+    /// Returns the sequential id of the pumpkin covering (x, y), `None` if the cell is empty or
+    /// the patch wasn't created [with sequential ids](PumpkinPatch::with_sequential_ids)
+    pub fn sequential_id(&self, x: u16, y: u16) -> Option<NonZeroU32> {
+        let id = self.get(x, y)?;
+        self.sequence.as_ref()?.by_origin.get(&id).copied()
+    }
+
+    /// Returns the square of the pumpkin with sequential id `seq`, `None` if it has since been
+    /// merged or removed
+    pub fn sequential_square(&self, seq: NonZeroU32) -> Option<Square> {
+        let sequence = self.sequence.as_ref()?;
+        let id = *sequence.origins.get(seq.get() as usize - 1)?;
+        (sequence.by_origin.get(&id) == Some(&seq)).then(|| self.pumpkin_square(id))
+    }
+
+    /// Adds the cells of `order` to an empty `size` x `size` patch, returning each cell along
+    /// with the square its insert produced
+    ///
+    /// Cells that can't be added are left out of the transcript, like with
+    /// [`PumpkinPatch::inserter`].
+    pub fn replay(size: u16, order: &[(u16, u16)], lookup_table: P) -> Vec<(u16, u16, Square)> {
+        let mut patch = Self::new(size, lookup_table);
+        order
+            .iter()
+            .filter_map(|&(x, y)| Some((x, y, patch.add(x, y).ok()?)))
+            .collect()
+    }
+
+    /// Creates a `size` x `size` patch with the cells set in `filled` already added
+    ///
+    /// `filled` is indexed by `y * size + x`. The cells are added in row-major order, starting
+    /// at (0, 0) and scanning along x before moving up a row. Each cell merges into the largest
+    /// pumpkin it can form, just like [`PumpkinPatch::add`], so the pumpkins depend on that
+    /// order: a fully set mask always becomes a single pumpkin, other masks may be covered by
+    /// different squares than another insertion order would give.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `filled` doesn't hold exactly `size * size` bits
+    pub fn from_bitmap(size: u16, filled: &BitVec, lookup_table: P) -> Self {
+        assert_eq!(
+            filled.len(),
+            size as usize * size as usize,
+            "mask doesn't match the grid size"
+        );
+        Self::from_rect_bitmap(size, size, &Bitmap::from(filled), lookup_table)
+    }
+
+    /// Creates a patch from a grid of `#` (filled) and `.` (empty) cells, the format written by
+    /// [`PumpkinPatch::render_mask`]
+    ///
+    /// The first line is the top row. Leading and trailing whitespace on each line and blank
+    /// lines are ignored, so masks can be indented inline. The grid size comes from the mask
+    /// and has to match `lookup_table`. Cells are added in the same order as
+    /// [`PumpkinPatch::from_bitmap`].
+    pub fn from_mask_str(s: &str, lookup_table: P) -> Result<Self, ParseError> {
+        let rows: Vec<&str> = s.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+        let width = rows.first().ok_or(ParseError::Empty)?.chars().count();
+        let height = rows.len();
+
+        let mut filled = Bitmap::new(width * height);
+        for (row, line) in rows.iter().enumerate() {
+            if line.chars().count() != width {
+                return Err(ParseError::Ragged {
+                    row,
+                    expected: width,
+                    found: line.chars().count(),
+                });
+            }
+
+            let y = height - 1 - row;
+            for (x, c) in line.chars().enumerate() {
+                match c {
+                    '#' => filled.set(y * width + x, true),
+                    '.' => {}
+                    _ => {
+                        return Err(ParseError::InvalidChar {
+                            row,
+                            col: x,
+                            found: c,
+                        })
+                    }
+                }
+            }
+        }
+
+        Self::from_mask(width, height, &filled, lookup_table)
+    }
+
+    /// [`PumpkinPatch::from_mask_str`] taking the mask as rows of booleans, top row first
+    ///
+    /// Returns [`ParseError::Empty`] if there are no rows or the first one is empty.
+    pub fn from_bool_grid(grid: &[Vec<bool>], lookup_table: P) -> Result<Self, ParseError> {
+        let width = grid.first().map_or(0, Vec::len);
+        if width == 0 {
+            return Err(ParseError::Empty);
+        }
+        let height = grid.len();
+
+        let mut filled = Bitmap::new(width * height);
+        for (row, line) in grid.iter().enumerate() {
+            if line.len() != width {
+                return Err(ParseError::Ragged {
+                    row,
+                    expected: width,
+                    found: line.len(),
+                });
+            }
+
+            let y = height - 1 - row;
+            for (x, _) in line.iter().enumerate().filter(|(_, &cell)| cell) {
+                filled.set(y * width + x, true);
+            }
+        }
+
+        Self::from_mask(width, height, &filled, lookup_table)
+    }
+
+    /// Reads the ids written by [`PumpkinPatch::to_rle`] back into a `size` x `size` patch
+    ///
+    /// The ids are taken as they are rather than re-added, so they have to describe square
+    /// pumpkins identified by their origin like [`PumpkinPatch::validate`] expects.
+    pub fn from_rle(s: &str, size: u16, lookup_table: P) -> Result<Self, ParseError> {
+        let (table_width, table_height) = (lookup_table.width(), lookup_table.height());
+        if (table_width, table_height) != (size, size) {
+            return Err(ParseError::TableMismatch {
+                width: table_width,
+                height: table_height,
+            });
+        }
+
+        let cells = size as usize * size as usize;
+        let mut ids = Vec::with_capacity(cells);
+        for (run, pair) in s.split_whitespace().enumerate() {
+            let parsed = pair
+                .split_once(':')
+                .and_then(|(count, id)| Some((count.parse::<usize>().ok()?, id.parse().ok()?)));
+            let Some((count, id)) = parsed.filter(|&(count, _)| count > 0) else {
+                return Err(ParseError::InvalidRun { run });
+            };
+            if count > cells - ids.len() {
+                return Err(ParseError::CellCount {
+                    expected: cells,
+                    found: ids.len().saturating_add(count),
+                });
+            }
+            ids.extend(core::iter::repeat_n(NonZeroU32::new(id), count));
+        }
+        if ids.len() != cells {
+            return Err(ParseError::CellCount {
+                expected: cells,
+                found: ids.len(),
+            });
+        }
+
+        let mut patch = Self::new(size, lookup_table);
+        patch.bitmap = Bitmap::from_fn(cells, |idx| ids[idx].is_some());
+        patch.filled = patch.bitmap.count_ones();
+        patch.ids = ids;
+        if patch.validate().is_err() {
+            return Err(ParseError::InvalidIds);
+        }
+
+        Ok(patch)
+    }
+
+    // Checks the mask fits the lookup table before adding its cells
+    fn from_mask(
+        width: usize,
+        height: usize,
+        filled: &Bitmap,
+        lookup_table: P,
+    ) -> Result<Self, ParseError> {
+        let (table_width, table_height) = (lookup_table.width(), lookup_table.height());
+        if (table_width as usize, table_height as usize) != (width, height) {
+            return Err(ParseError::TableMismatch {
+                width: table_width,
+                height: table_height,
+            });
+        }
+
+        Ok(Self::from_rect_bitmap(
+            width as u16,
+            height as u16,
+            filled,
+            lookup_table,
+        ))
+    }
+
+    // Adds the set cells of `filled` in row-major order. The patch starts without a history,
+    // `undo` only reverts the caller's own adds.
+    fn from_rect_bitmap(width: u16, height: u16, filled: &Bitmap, lookup_table: P) -> Self {
+        let mut patch = Self::new_rect(width, height, lookup_table);
+        debug_assert_eq!(filled.len(), patch.bitmap.len());
+
+        for idx in (0..filled.len()).filter(|&idx| filled[idx]) {
+            let (x, y) = (idx % width as usize, idx / width as usize);
+            patch.merge(x as u16, y as u16).ok();
+        }
+        patch.clear_history();
+
+        patch
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /// The cap set by [`PumpkinPatch::with_max_square_size`]
+    pub fn max_square_size(&self) -> Option<u16> {
+        self.max_square_size
+    }
+
+    /// The minimum set by [`PumpkinPatch::with_min_square_size`]
+    pub fn min_square_size(&self) -> Option<u16> {
+        self.min_square_size
+    }
+
+    /// How the edges of the grid behave, this comes from the patch's [`LookupTable`]
+    pub fn topology(&self) -> Topology {
+        self.lookup_table.topology()
+    }
+
+    /// The policy set by [`PumpkinPatch::with_tie_break`]
+    pub fn tie_break(&self) -> TieBreak {
+        self.tie_break
+    }
+
+    /// Returns the square of the pumpkin covering (x, y), or `None` if the cell is empty
+    ///
+    /// On a toroidal grid the square can extend past the far edges, its cells wrap around.
+    pub fn get_square_at(&self, x: u16, y: u16) -> Option<Square> {
+        self.get(x, y).map(|id| self.pumpkin_square(id))
+    }
+
+    /// Returns the ids of the other pumpkins sharing an edge with pumpkin `id`, in increasing
+    /// order
+    ///
+    /// These are the cells just outside each side of its square, the ones `check_boundary`
+    /// compares against, wrapping around on a torus. An id that isn't in the patch has no
+    /// neighbors.
+    pub fn pumpkin_neighbors(&self, id: NonZeroU32) -> Vec<NonZeroU32> {
+        if self.ids.get(id.get() as usize - 1) != Some(&Some(id)) {
+            return Vec::new();
+        }
+
+        let square = self.pumpkin_square(id);
+        let (x0, y0, size) = (square.x as i32, square.y as i32, square.size() as i32);
+        let (width, height) = (self.width as i32, self.height as i32);
+        let outside = (0..size).flat_map(|i| {
+            [
+                (x0 + i, y0 + size), // north
+                (x0 + i, y0 - 1),    // south
+                (x0 + size, y0 + i), // east
+                (x0 - 1, y0 + i),    // west
+            ]
+        });
+
+        let mut neighbors: Vec<_> = outside
+            .filter_map(|(x, y)| match self.topology() {
+                Topology::Bounded => {
+                    let in_grid = (0..width).contains(&x) && (0..height).contains(&y);
+                    in_grid.then(|| self.get(x as u16, y as u16))
+                }
+                Topology::Toroidal => Some(self.get(wrap(x, self.width), wrap(y, self.height))),
+            })
+            .flatten()
+            .filter(|&other| other != id)
+            .collect();
+        neighbors.sort_unstable();
+        neighbors.dedup();
+        neighbors
+    }
+
+    /// Iterates over every pumpkin in the patch along with its square, in row-major order of the
+    /// pumpkins' origins
+    pub fn pumpkins(&self) -> impl Iterator<Item = (NonZeroU32, Square)> + '_ {
+        // Ids encode their origin, so each pumpkin is reported once at its origin cell
+        self.ids.iter().enumerate().filter_map(|(idx, id)| {
+            let id = (*id)?;
+            (id.get() as usize - 1 == idx).then(|| (id, self.pumpkin_square(id)))
+        })
+    }
+
+    /// Number of distinct pumpkins in the patch
+    pub fn pumpkin_count(&self) -> usize {
+        self.pumpkins().count()
+    }
+
+    /// The size of the pumpkin covering each cell, `0` for empty ones, indexed by
+    /// `y * width + x` like the ids
+    pub fn size_map(&self) -> Vec<u16> {
+        let mut sizes = vec![0; self.ids.len()];
+        for (_, sq) in self.pumpkins() {
+            for (x, y) in sq.iter_cells_toroidal(self.width, self.height) {
+                sizes[self.index(x, y)] = sq.size();
+            }
+        }
+        sizes
+    }
+
+    /// Number of pumpkins of each size, sizes without pumpkins are left out
+    pub fn size_histogram(&self) -> BTreeMap<u16, usize> {
+        let mut histogram = BTreeMap::new();
+        for (_, sq) in self.pumpkins() {
+            *histogram.entry(sq.size()).or_insert(0) += 1;
+        }
+        histogram
+    }
+
+    /// Number of filled cells
+    pub fn filled_cells(&self) -> usize {
+        self.filled
+    }
+
+    /// Number of empty cells
+    pub fn remaining_cells(&self) -> usize {
+        self.bitmap.len() - self.filled
+    }
+
+    /// Returns the largest square of empty cells that fits in the grid without wrapping, `None`
+    /// if every cell is filled
+    ///
+    /// Of several equally large squares the one whose origin comes first in row-major order is
+    /// returned.
+    pub fn largest_empty_square(&self) -> Option<Square> {
+        let sizes = self.origin_square_sizes(false);
+        let mut best: Option<Square> = None;
+        for (idx, &size) in sizes.iter().enumerate() {
+            if size > best.map_or(0, |sq| sq.size()) {
+                let (x, y) = self.cell(idx);
+                best = Some(Square::new(x, y, size));
+            }
+        }
+        best
+    }
+
+    /// Returns every filled square that isn't inside a larger filled square, in row-major
+    /// order of their origins
+    ///
+    /// Unlike [`PumpkinPatch::pumpkins`] this only looks at which cells are filled, a maximal
+    /// square can cover several pumpkins or parts of them. Squares don't wrap around a torus.
+    pub fn maximal_squares(&self) -> Vec<Square> {
+        let sizes = self.origin_square_sizes(true);
+        let width = self.width as usize;
+
+        // A square that isn't the largest at its origin is inside that one. Otherwise a square
+        // one larger containing it has its origin one step south and/or west.
+        let size_at = |x: usize, y: usize| sizes[y * width + x];
+        (0..sizes.len())
+            .filter(|&idx| {
+                let (x, y) = (idx % width, idx / width);
+                let size = sizes[idx];
+                size > 0
+                    && !(x > 0 && size_at(x - 1, y) > size
+                        || y > 0 && size_at(x, y - 1) > size
+                        || x > 0 && y > 0 && size_at(x - 1, y - 1) > size)
+            })
+            .map(|idx| {
+                let (x, y) = self.cell(idx);
+                Square::new(x, y, sizes[idx])
+            })
+            .collect()
+    }
+
+    // For every cell, the size of the largest square with its origin there whose cells are all
+    // filled (or all empty) without wrapping, indexed like `ids`
+    fn origin_square_sizes(&self, filled: bool) -> Vec<u16> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let mut sizes = vec![0u16; width * height];
+        let size_at = |sizes: &[u16], x: usize, y: usize| {
+            if x < width && y < height {
+                sizes[y * width + x]
+            } else {
+                0
+            }
+        };
+
+        for y in (0..height).rev() {
+            for x in (0..width).rev() {
+                if self.bitmap[y * width + x] == filled {
+                    sizes[y * width + x] = 1 + size_at(&sizes, x + 1, y)
+                        .min(size_at(&sizes, x, y + 1))
+                        .min(size_at(&sizes, x + 1, y + 1));
+                }
+            }
+        }
+
+        sizes
+    }
+
+    // Inverse of `index`
+    fn cell(&self, idx: usize) -> (u16, u16) {
+        let width = self.width as usize;
+        ((idx % width) as u16, (idx / width) as u16)
+    }
+
+    /// Iterates over the cells that aren't filled yet in row-major order, starting at (0, 0)
+    pub fn empty_cells(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        let width = self.width as usize;
+        (0..self.bitmap.len())
+            .filter(|&idx| !self.bitmap[idx])
+            .map(move |idx| ((idx % width) as u16, (idx / width) as u16))
+    }
+
+    /// Groups the empty cells into regions connected through their edges
+    ///
+    /// Regions are ordered by their first cell and list their cells in row-major order. On a
+    /// torus regions connect across the far edges.
+    pub fn empty_regions(&self) -> Vec<Vec<(u16, u16)>> {
+        let (width, height) = (self.width as i32, self.height as i32);
+        let mut seen = self.bitmap.clone();
+        let mut stack = Vec::new();
+        let mut regions = Vec::new();
+
+        for start in self.empty_cells() {
+            if seen[self.index(start.0, start.1)] {
+                continue;
+            }
+            seen.set(self.index(start.0, start.1), true);
+            stack.push(start);
+
+            let mut region = Vec::new();
+            while let Some((x, y)) = stack.pop() {
+                region.push((x, y));
+                let (x, y) = (x as i32, y as i32);
+                for (nx, ny) in [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)] {
+                    let next = match self.topology() {
+                        Topology::Bounded => ((0..width).contains(&nx)
+                            && (0..height).contains(&ny))
+                        .then_some((nx as u16, ny as u16)),
+                        Topology::Toroidal => Some((wrap(nx, self.width), wrap(ny, self.height))),
+                    };
+                    if let Some((nx, ny)) = next {
+                        let idx = self.index(nx, ny);
+                        if !seen[idx] {
+                            seen.set(idx, true);
+                            stack.push((nx, ny));
+                        }
+                    }
+                }
+            }
+
+            region.sort_unstable_by_key(|&(x, y)| (y, x));
+            regions.push(region);
+        }
+
+        regions
+    }
+
+    /// Returns true once every cell is filled
+    pub fn is_full(&self) -> bool {
+        self.remaining_cells() == 0
+    }
+
+    /// Fraction of the grid that is filled, between 0.0 and 1.0
+    pub fn coverage(&self) -> f64 {
+        if self.bitmap.is_empty() {
+            return 0.0;
+        }
+        self.filled_cells() as f64 / self.bitmap.len() as f64
+    }
+
+    /// Empties the patch in place, keeping the lookup table and the allocations
+    pub fn clear(&mut self) {
+        self.bitmap.clear();
+        self.filled = 0;
+        self.ids.fill(None);
+        if let Some(sequence) = &mut self.sequence {
+            *sequence = Sequence::default();
+        }
+        self.clear_history();
+    }
+
+    /// Saves the filled cells and the pumpkins so [`PumpkinPatch::restore`] can go back to them
+    ///
+    /// Unlike cloning the patch this leaves out the lookup table, the undo history and the
+    /// search buffers.
+    pub fn snapshot(&self) -> PatchSnapshot {
+        PatchSnapshot {
+            width: self.width,
+            height: self.height,
+            bitmap: self.bitmap.clone(),
+            ids: self.ids.clone(),
+            filled: self.filled,
+            sequence: self.sequence.clone(),
+        }
+    }
+
+    /// Puts the patch back in the state `snapshot` was taken in, reusing its allocations
+    ///
+    /// The undo history is forgotten, like after [`PumpkinPatch::clear`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the snapshot was taken from a patch with a different width or height
+    pub fn restore(&mut self, snapshot: &PatchSnapshot) {
+        assert_eq!(
+            (self.width, self.height),
+            (snapshot.width, snapshot.height),
+            "snapshot is from a patch with different dimensions"
+        );
+        self.bitmap.clone_from(&snapshot.bitmap);
+        self.ids.clone_from(&snapshot.ids);
+        self.filled = snapshot.filled;
+        self.sequence.clone_from(&snapshot.sequence);
+        self.clear_history();
+    }
+
+    /// Checks the patch's internal invariants, describing the first violation found
+    ///
+    /// A cell has to be filled exactly when it has an id, except for cells deferred by a
+    /// minimum size, and the cells sharing an id have to form exactly the square of that
+    /// pumpkin.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut cells_per_id = BTreeMap::new();
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let id = self.get(x, y);
+                let deferred = id.is_none() && self.min_square_size.is_some();
+                if id.is_some() != self.contains(x, y) && !deferred {
+                    return Err(format!(
+                        "cell ({}, {}) has id {:?} but filled is {}",
+                        x,
+                        y,
+                        id,
+                        self.contains(x, y)
+                    ));
+                }
+                if let Some(id) = id {
+                    *cells_per_id.entry(id).or_insert(0u32) += 1;
+                }
+            }
+        }
+
+        let filled = self.bitmap.count_ones();
+        if filled != self.filled {
+            return Err(format!(
+                "{} cells are filled but the count is {}",
+                filled, self.filled
+            ));
+        }
+
+        // Ids are visited in order so the error always names the same cell
+        let mut ids: Vec<_> = cells_per_id.into_iter().collect();
+        ids.sort_unstable();
+        for (id, count) in ids {
+            let origin = id.get() as usize - 1;
+            let (x, y) = (
+                (origin % self.width as usize) as u16,
+                (origin / self.width as usize) as u16,
+            );
+            if origin >= self.ids.len() || self.get(x, y) != Some(id) {
+                return Err(format!(
+                    "pumpkin {} doesn't cover its origin cell ({}, {})",
+                    id, x, y
+                ));
+            }
+
+            let square = self.pumpkin_square(id);
+            if !self.lookup_table.holds(square) {
+                return Err(format!("pumpkin {} is {:?}, outside the grid", id, square));
+            }
+            if self.max_square_size.is_some_and(|max| square.size() > max) {
+                return Err(format!(
+                    "pumpkin {} is {:?}, larger than the max size {}",
+                    id,
+                    square,
+                    self.max_square_size.unwrap()
+                ));
+            }
+            if self.min_square_size.is_some_and(|min| square.size() < min) {
+                return Err(format!(
+                    "pumpkin {} is {:?}, smaller than the min size {}",
+                    id,
+                    square,
+                    self.min_square_size.unwrap()
+                ));
+            }
+            if let Some((cx, cy)) = square
+                .iter_cells_toroidal(self.width, self.height)
+                .find(|&(cx, cy)| self.get(cx, cy) != Some(id))
+            {
+                return Err(format!(
+                    "cell ({}, {}) is inside pumpkin {} but has id {:?}",
+                    cx,
+                    cy,
+                    id,
+                    self.get(cx, cy)
+                ));
+            }
+            if count != square.cell_count() {
+                return Err(format!(
+                    "pumpkin {} is {:?} but {} cells have its id",
+                    id, square, count
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn index(&self, x: u16, y: u16) -> usize {
+        y as usize * self.width as usize + x as usize
+    }
+
+    /// Returns the id of the pumpkin covering (x, y), `None` if the cell is empty or outside of
+    /// the grid
+    pub fn get(&self, x: u16, y: u16) -> Option<NonZeroU32> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        self.ids[self.index(x, y)]
+    }
+
+    /// Returns true if (x, y) is filled, cells outside of the grid never are
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        x < self.width && y < self.height && self.bitmap[self.index(x, y)]
+    }
+
+    /// Returns true if both cells are filled and belong to the same pumpkin, cells outside of
+    /// the grid belong to none
+    pub fn same_pumpkin(&self, a: (u16, u16), b: (u16, u16)) -> bool {
+        let id = self.get(a.0, a.1);
+        id.is_some() && id == self.get(b.0, b.1)
+    }
+
+    /// The ids of row `y` from west to east, ids are stored row by row so this doesn't copy
+    ///
+    /// # Panics
+    ///
+    /// Panics if `y` is outside of the grid
+    pub fn row(&self, y: u16) -> &[Option<NonZeroU32>] {
+        assert!(
+            y < self.height,
+            "row {} out of range for height {}",
+            y,
+            self.height
+        );
+        let start = self.index(0, y);
+        &self.ids[start..start + self.width as usize]
+    }
+
+    /// The distinct ids of the pumpkins with a cell inside the `w` x `h` box with origin (x, y)
+    ///
+    /// The box is clipped to the grid, parts outside of it don't wrap around even on a torus.
+    pub fn ids_in_region(&self, x: u16, y: u16, w: u16, h: u16) -> BTreeSet<NonZeroU32> {
+        let x_end = x.saturating_add(w).min(self.width);
+        let y_end = y.saturating_add(h).min(self.height);
+
+        (y.min(y_end)..y_end)
+            .flat_map(|y| &self.row(y)[x.min(x_end) as usize..x_end as usize])
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// Width of the columns [`Display`](core::fmt::Display) pads the ids to, the number of
+    /// digits of the largest id but at least 3
+    pub fn display_width(&self) -> usize {
+        id_column_width(&self.ids)
+    }
+
+    /// The ids as one `Vec` per row with `0` for empty cells, top row first like
+    /// [`Display`](core::fmt::Display)
+    pub fn to_grid(&self) -> Vec<Vec<u32>> {
+        (0..self.height)
+            .rev()
+            .map(|y| {
+                self.row(y)
+                    .iter()
+                    .map(|id| id.map_or(0, NonZeroU32::get))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The ids of column `x` from south to north
+    ///
+    /// # Panics
+    ///
+    /// Panics if `x` is outside of the grid
+    pub fn column(&self, x: u16) -> impl Iterator<Item = Option<NonZeroU32>> + '_ {
+        assert!(
+            x < self.width,
+            "column {} out of range for width {}",
+            x,
+            self.width
+        );
+        self.ids[x as usize..]
+            .iter()
+            .step_by(self.width as usize)
+            .copied()
+    }
+
+    fn check_boundary(&self, sq: &Square) -> bool {
+        if self.topology() == Topology::Toroidal {
+            return self.check_boundary_toroidal(sq);
+        }
+
+        trace!("Checking boundary for {:?}", sq);
+
+        let size = sq.size() as usize;
+        let (x, y) = (sq.x as usize, sq.y as usize);
+        let (width, height) = (self.width as usize, self.height as usize);
+
+        // The south and north sides are contiguous runs of `ids`, the west and east sides are
+        // columns, so they step a whole row at a time. The cells just outside a side are `width`
+        // entries away for rows and one entry away for columns.
+        let south = self.index(sq.x, sq.y);
+        let north = south + (size - 1) * width;
+        let west = south;
+        let east = south + size - 1;
+
+        // north is +y
+        if y + size < height && splits(&self.ids, north, north + width, size, 1, "NORTH") {
+            return false;
+        }
+        // south is -y
+        if y > 0 && splits(&self.ids, south, south - width, size, 1, "SOUTH") {
+            return false;
+        }
+        // east is +x
+        if x + size < width && splits(&self.ids, east, east + 1, size, width, "EAST") {
+            return false;
+        }
+        // west is -x
+        if x > 0 && splits(&self.ids, west, west - 1, size, width, "WEST") {
+            return false;
+        }
+
+        true
+    }
+
+    // `check_boundary` for a torus. The rows and columns just outside the square wrap around, so
+    // they are no longer contiguous slices and are compared cell by cell instead. A square that
+    // spans the whole height (or width) has no outside row (or column) to check.
+    fn check_boundary_toroidal(&self, sq: &Square) -> bool {
+        let (x0, y0, size) = (sq.x as i32, sq.y as i32, sq.size() as i32);
+        let at = |x: i32, y: i32| self.get(wrap(x, self.width), wrap(y, self.height));
+        let crosses = |inside: Option<NonZeroU32>, outside: Option<NonZeroU32>| {
+            outside.is_some() && inside == outside
+        };
+
+        if size < self.height as i32 {
+            for x in x0..x0 + size {
+                // north and south
+                if crosses(at(x, y0 + size - 1), at(x, y0 + size))
+                    || crosses(at(x, y0), at(x, y0 - 1))
+                {
+                    return false;
+                }
+            }
+        }
+
+        if size < self.width as i32 {
+            for y in y0..y0 + size {
+                // east and west
+                if crosses(at(x0 + size - 1, y), at(x0 + size, y))
+                    || crosses(at(x0, y), at(x0 - 1, y))
+                {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Fills the cell at (x, y) and merges it into the largest pumpkin it can form
+    ///
+    /// With a minimum size set, a square smaller than it isn't committed: the cell is filled
+    /// and [`AddError::Deferred`] is returned.
+    pub fn add(&mut self, x: u16, y: u16) -> Result<Square, AddError> {
+        self.check_empty(x, y)?;
+        self.merge(x, y)
+    }
+
+    /// Lists every cell whose id is different in `other`, in row-major order
+    ///
+    /// `from` is the id in `self` and `to` the id in `other`, so diffing a patch against its
+    /// state after an `add` gives the cells to redraw.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the patches don't have the same width and height
+    pub fn diff<Q>(&self, other: &PumpkinPatch<Q>) -> Vec<CellChange> {
+        assert_eq!(
+            (self.width, self.height),
+            (other.width, other.height),
+            "patches have different dimensions"
+        );
+
+        (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+            .zip(self.ids.iter().zip(&other.ids))
+            .filter(|(_, (from, to))| from != to)
+            .map(|((x, y), (&from, &to))| CellChange { x, y, from, to })
+            .collect()
+    }
+
+    /// [`PumpkinPatch::add`], also reporting how much work the merge search did
+    ///
+    /// Plain `add` skips the counting, use this to compare the cost of insert orders.
+    pub fn add_with_stats(&mut self, x: u16, y: u16) -> Result<(Square, AddStats), AddError> {
+        self.check_empty(x, y)?;
+
+        let mut stats = AddStats::default();
+        let mut search = core::mem::take(&mut self.search);
+        let square = self.largest_square_with(x, y, &mut search, Some(&mut stats));
+        self.search = search;
+
+        self.commit(x, y, square)?;
+        Ok((square, stats))
+    }
+
+    /// [`PumpkinPatch::add`], calling `hook` if the cell merged into a pumpkin larger than 1x1
+    ///
+    /// `hook` is called at most once, after the patch has been updated.
+    pub fn add_with_hook<F: FnMut(MergeEvent)>(
+        &mut self,
+        x: u16,
+        y: u16,
+        mut hook: F,
+    ) -> Result<Square, AddError> {
+        self.check_empty(x, y)?;
+        let square = self.find_square(x, y);
+
+        let mut absorbed: Vec<_> = square
+            .iter_cells_toroidal(self.width, self.height)
+            .filter_map(|(cx, cy)| self.get(cx, cy))
+            .collect();
+        absorbed.sort_unstable();
+        absorbed.dedup();
+
+        self.commit(x, y, square)?;
+        if square.size() > 1 {
+            hook(MergeEvent { square, absorbed });
+        }
+        Ok(square)
+    }
+
+    /// Adds every cell of `cells` in order, returning the square each insert produced
+    ///
+    /// Equivalent to calling [`PumpkinPatch::add`] on each cell, but the search buffers are
+    /// taken out of the patch once for the whole batch. Like [`PumpkinPatch::inserter`], cells
+    /// that can't be added or are deferred are skipped and have no square in the result.
+    pub fn add_many(&mut self, cells: &[(u16, u16)]) -> Vec<Square> {
+        let mut search = core::mem::take(&mut self.search);
+        let squares = cells
+            .iter()
+            .filter_map(|&(x, y)| {
+                self.check_empty(x, y).ok()?;
+                let square = self.largest_square(x, y, &mut search);
+                self.commit(x, y, square).ok()
+            })
+            .collect();
+        self.search = search;
+        squares
+    }
+
+    /// Adds every empty cell in an order shuffled by `rng`, returning the square each one made
+    ///
+    /// Cells [`PumpkinPatch::add_many`] would skip, like ones left over by a minimum square size,
+    /// don't get a square.
+    pub fn random_fill(&mut self, rng: &mut impl Rng) -> Vec<Square> {
+        let mut order: Vec<_> = self.empty_cells().collect();
+        order.shuffle(rng);
+        self.add_many(&order)
+    }
+
+    /// [`PumpkinPatch::add`], also listing the cells that belonged to another pumpkin before
+    ///
+    /// These are the cells of the absorbed pumpkins, the ones to redraw besides (x, y). Ids
+    /// encode the origin of a pumpkin, so a pumpkin sharing its origin with the new square
+    /// keeps its id but its cells are listed all the same. The list is empty if the cell didn't
+    /// merge.
+    pub fn add_tracked(&mut self, x: u16, y: u16) -> Result<(Square, Vec<(u16, u16)>), AddError> {
+        self.check_empty(x, y)?;
+        let square = self.find_square(x, y);
+
+        let absorbed = square
+            .iter_cells_toroidal(self.width, self.height)
+            .filter(|&(cx, cy)| self.get(cx, cy).is_some())
+            .collect();
+
+        self.commit(x, y, square)?;
+        Ok((square, absorbed))
+    }
+
+    /// Returns an iterator that adds the cells of `order` one at a time, yielding the square
+    /// each insert produced. Cells that can't be added or are deferred are skipped.
+    pub fn inserter(&mut self, order: Vec<(u16, u16)>) -> Inserter<'_, P> {
+        Inserter {
+            patch: self,
+            order: order.into_iter(),
+        }
+    }
+
+    /// Returns the square `add(x, y)` would produce without changing the patch
+    pub fn preview(&self, x: u16, y: u16) -> Result<Square, AddError> {
+        self.check_empty(x, y)?;
+        let square = self.largest_square(x, y, &mut Search::default());
+        self.check_min_size(square)
+    }
+
+    fn check_empty(&self, x: u16, y: u16) -> Result<(), AddError> {
+        if x >= self.width || y >= self.height {
+            return Err(AddError::OutOfBounds);
+        }
+        if self.contains(x, y) {
+            return Err(AddError::AlreadyFilled);
+        }
+        Ok(())
+    }
+
+    fn check_min_size(&self, square: Square) -> Result<Square, AddError> {
+        if self.min_square_size.is_some_and(|min| square.size() < min) {
+            return Err(AddError::Deferred);
+        }
+        Ok(square)
+    }
+
+    // Fills (x, y) and assigns the largest square it forms, unless that square is below the
+    // minimum size
+    fn merge(&mut self, x: u16, y: u16) -> Result<Square, AddError> {
+        let square = self.find_square(x, y);
+        self.commit(x, y, square)
+    }
+
+    // `largest_square` using the patch's own search buffers
+    fn find_square(&mut self, x: u16, y: u16) -> Square {
+        debug_assert!(!self.contains(x, y));
+
+        let mut search = core::mem::take(&mut self.search);
+        let largest_square = self.largest_square(x, y, &mut search);
+        self.search = search;
+        largest_square
+    }
+
+    // Fills (x, y) and gives every cell of `largest_square` its id
+    fn commit(&mut self, x: u16, y: u16, largest_square: Square) -> Result<Square, AddError> {
+        self.bitmap.set(self.index(x, y), true);
+        self.filled += 1;
+        if let Err(err) = self.check_min_size(largest_square) {
+            if let Some(history) = &mut self.history {
+                history.inserts.push(Insert {
+                    x,
+                    y,
+                    square: None,
+                    absorbed: 0,
+                });
+            }
+            return Err(err);
+        }
+
+        // Remember the pumpkins the new square absorbs, they lie entirely inside it so each one
+        // is found at its origin. Patches without undo or sequential ids skip the walk.
+        let mut absorbed = 0;
+        let mut history = self.history.take();
+        if history.is_some() || self.sequence.is_some() {
+            for (cx, cy) in largest_square.iter_cells_toroidal(self.width, self.height) {
+                let idx = self.index(cx, cy);
+                let Some(old) = self.ids[idx].filter(|old| old.get() as usize - 1 == idx) else {
+                    continue;
+                };
+                if let Some(history) = &mut history {
+                    history.absorbed.push(self.pumpkin_square(old));
+                    absorbed += 1;
+                }
+                if let Some(sequence) = &mut self.sequence {
+                    sequence.retire(old);
+                }
+            }
+        }
+        if let Some(history) = &mut history {
+            history.inserts.push(Insert {
+                x,
+                y,
+                square: Some(largest_square),
+                absorbed,
+            });
+        }
+        self.history = history;
+
+        // Fill the ids with the new square
+        let id = pumpkin_id(largest_square.x, largest_square.y, self.width);
+        if let Some(sequence) = &mut self.sequence {
+            sequence.push(id);
+        }
+        self.fill_ids(largest_square, Some(id));
+
+        Ok(largest_square)
+    }
+
+    /// DFS algorithm to fund the largest square containing (x, y) that can be merged into a bigger pumpkin
+    ///
+    /// (x, y) is treated as filled whether or not it's set in the bitmap. `search` is left
+    /// cleared so it can be passed to the next call.
+    fn largest_square(&self, x: u16, y: u16, search: &mut Search) -> Square {
+        self.largest_square_with(x, y, search, None)
+    }
+
+    // `largest_square`, also counting the work done into `stats` when it's given
+    fn largest_square_with(
+        &self,
+        x: u16,
+        y: u16,
+        search: &mut Search,
+        mut stats: Option<&mut AddStats>,
+    ) -> Square {
+        let mut largest_square = Square::new(x, y, 1);
+        let max_size = self.max_size();
+        let tie_break = self.tie_break;
+        let mut popped = 0;
+        let count = stats.is_some().then_some(&mut popped);
+
+        self.walk_squares(x, y, search, count, |square| {
+            if let Some(stats) = stats.as_deref_mut() {
+                stats.max_square_tried = stats.max_square_tried.max(square.size());
+            }
+            let better = square.size > largest_square.size
+                || square.size == largest_square.size && tie_break.prefers(square, largest_square);
+            if better {
+                if let Some(stats) = stats.as_deref_mut() {
+                    stats.boundary_checks += 1;
+                }
+                if self.check_boundary(&square) {
+                    largest_square = square;
+                    // Nothing can beat a square of the largest allowed size, unless the tie
+                    // break has to see all of them
+                    return square.size() == max_size && tie_break == TieBreak::FirstFound;
+                }
+            }
+            false
+        });
+
+        if let Some(stats) = stats {
+            stats.squares_visited = popped;
+        }
+        largest_square
+    }
+
+    /// Returns every square containing the empty cell (x, y) that `add(x, y)` could merge into,
+    /// smallest first
+    ///
+    /// These are the squares whose other cells are all filled and which don't cut through a
+    /// pumpkin, the last one is what [`PumpkinPatch::preview`] picks among. Squares of the same
+    /// size are ordered by their origin, row by row. A filled or out of bounds cell has no
+    /// candidates.
+    pub fn candidates(&self, x: u16, y: u16) -> Vec<Square> {
+        if self.check_empty(x, y).is_err() {
+            return Vec::new();
+        }
+
+        let mut candidates = Vec::new();
+        self.walk_squares(x, y, &mut Search::default(), None, |square| {
+            if self.check_boundary(&square) {
+                candidates.push(square);
+            }
+            false
+        });
+
+        candidates.sort_by_key(|sq| (sq.size(), sq.y, sq.x));
+        candidates
+    }
+
+    // The largest square the search may reach, the table's size unless a smaller cap is set
+    fn max_size(&self) -> u16 {
+        let size = self.lookup_table.size();
+        self.max_square_size.map_or(size, |max| max.min(size))
+    }
+
+    // Walks the squares containing (x, y) whose other cells are all filled, growing from
+    // (x, y) itself. `visit` is called once for each of them and stops the walk by returning
+    // true. When `popped` is given it's set to the number of squares taken off the stack.
+    fn walk_squares(
+        &self,
+        x: u16,
+        y: u16,
+        search: &mut Search,
+        mut popped: Option<&mut usize>,
+        mut visit: impl FnMut(Square) -> bool,
+    ) {
+        let start = Square::new(x, y, 1);
+
+        let table = &*self.lookup_table;
+        let Search {
+            visited,
+            touched,
+            stack,
+        } = search;
+        if visited.len() != table.num_squares() {
+            *visited = Bitmap::new(table.num_squares());
+        }
+        debug_assert!(visited.none() && touched.is_empty() && stack.is_empty());
+
+        visited.set(table.idx(start), true);
+        touched.push(table.idx(start));
+        stack.push(start);
+        if let Some(popped) = popped.as_deref_mut() {
+            *popped = 0;
+        }
+
+        // Every square in the search contains (x, y), which isn't filled yet, so its bit is skipped
+        // and only the other cells have to be filled.
+        let cell = self.index(x, y);
+        let max_size = self.max_size();
+
+        while let Some(square) = stack.pop() {
+            if let Some(popped) = popped.as_deref_mut() {
+                *popped += 1;
+            }
+            let bitmap = table.get_bitmap(square);
+            debug_assert_eq!(*bitmap, table.square_bitmap(square));
+
+            if bitmap.is_subset_except(&self.bitmap, cell, table.bit_span(square)) {
+                // Squares as large as the table have nothing larger to expand into, and ones at
+                // the cap aren't allowed to
+                if square.size() < max_size {
+                    for sq in table.get_larger(square) {
+                        let idx = table.idx(sq);
+                        if !visited[idx] {
+                            visited.set(idx, true);
+                            touched.push(idx);
+                            stack.push(sq);
+                        }
+                    }
+                }
+
+                if visit(square) {
+                    stack.clear();
+                    break;
+                }
+            }
+        }
+
+        // Only a handful of squares are visited, resetting them is cheaper than clearing the
+        // whole table sized buffer
+        for idx in touched.drain(..) {
+            visited.set(idx, false);
+        }
+    }
+
+    /// Removes the cell at (x, y), splitting up the pumpkin it belonged to
+    ///
+    /// Every other cell of the pumpkin is emptied and then re-added in row-major order, so the
+    /// remaining cells merge again wherever they still can. For example removing the top-right
+    /// cell of a size-3 pumpkin re-forms a size-2 pumpkin in its bottom-left corner and leaves
+    /// the other 4 cells as size-1 pumpkins, while removing its center cell leaves 8 size-1
+    /// pumpkins. Removing an empty cell does nothing, a deferred cell is simply emptied.
+    pub fn remove(&mut self, x: u16, y: u16) {
+        let Some(id) = self.get(x, y) else {
+            if self.contains(x, y) {
+                self.bitmap.set(self.index(x, y), false);
+                self.filled -= 1;
+                self.clear_history();
+            }
+            return;
+        };
+
+        let square = self.pumpkin_square(id);
+        if let Some(sequence) = &mut self.sequence {
+            sequence.retire(id);
+        }
+        for (cx, cy) in square.iter_cells_toroidal(self.width, self.height) {
+            let idx = self.index(cx, cy);
+            self.bitmap.set(idx, false);
+        }
+        self.filled -= square.cell_count() as usize;
+        self.fill_ids(square, None);
+
+        for (cx, cy) in square.iter_cells_toroidal(self.width, self.height) {
+            if (cx, cy) != (x, y) {
+                self.merge(cx, cy).ok();
+            }
+        }
+        // The earlier adds no longer line up with the grid and the re-merges aren't the
+        // caller's to undo
+        self.clear_history();
+    }
+
+    /// Reverts the most recent `add`, returning the square it produced
+    ///
+    /// The added cell is emptied and the pumpkins merged into that square get their ids back.
+    /// For an `add` that was deferred the 1x1 square of the cell is returned. Every `add` can be
+    /// undone in reverse order, until a `remove` or `clear` forgets them, after which `undo`
+    /// returns `None`. Patches without [`PumpkinPatch::with_undo`] keep no history and always
+    /// return `None`.
+    pub fn undo(&mut self) -> Option<Square> {
+        let insert = self.history.as_mut()?.inserts.pop()?;
+        let idx = self.index(insert.x, insert.y);
+        self.bitmap.set(idx, false);
+        self.filled -= 1;
+
+        let Some(square) = insert.square else {
+            return Some(Square::new(insert.x, insert.y, 1));
+        };
+        self.fill_ids(square, None);
+        if let Some(sequence) = &mut self.sequence {
+            sequence.pop();
+        }
+
+        for _ in 0..insert.absorbed {
+            let absorbed = self.history.as_mut().unwrap().absorbed.pop().unwrap();
+            let id = pumpkin_id(absorbed.x, absorbed.y, self.width);
+            self.fill_ids(absorbed, Some(id));
+            if let Some(sequence) = &mut self.sequence {
+                sequence.restore(id);
+            }
+        }
+
+        Some(square)
+    }
+
+    // Forgets the adds `undo` could revert, keeping the history enabled
+    fn clear_history(&mut self) {
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+    }
+
+    // Reconstructs the square of the pumpkin with the given id. Ids encode the pumpkin's origin,
+    // the size is found by walking east from there, wrapping around on a torus.
+    fn pumpkin_square(&self, id: NonZeroU32) -> Square {
+        let origin = id.get() - 1;
+        let width = self.width as u32;
+        let (x, y) = ((origin % width) as u16, (origin / width) as u16);
+        debug_assert_eq!(self.get(x, y), Some(id));
+
+        let size = match self.topology() {
+            Topology::Bounded => {
+                let row = &self.ids[self.index(x, y)..self.index(0, y) + self.width as usize];
+                row.iter().take_while(|&&cell| cell == Some(id)).count()
+            }
+            Topology::Toroidal => (x as i32..x as i32 + self.width as i32)
+                .take_while(|&cx| self.get(wrap(cx, self.width), y) == Some(id))
+                .count(),
+        };
+
+        Square::new(x, y, size as u16)
+    }
+
+    // Sets the id of every cell in the square
+    fn fill_ids(&mut self, square: Square, id: Option<NonZeroU32>) {
+        // Wrapping leaves the cells of bounded squares unchanged
+        for (x, y) in square.iter_cells_toroidal(self.width, self.height) {
+            let idx = self.index(x, y);
+            self.ids[idx] = id;
+        }
+    }
+}
+
+// Returns true if a pumpkin crosses from the `size` ids starting at `inside` into the ones starting
+// at `outside`, used by `check_boundary` on one side of a square. Consecutive ids of a side are
+// `stride` entries apart.
+fn splits(
+    ids: &[Option<NonZeroU32>],
+    inside: usize,
+    outside: usize,
+    size: usize,
+    stride: usize,
+    side: &str,
+) -> bool {
+    let inside = ids[inside..].iter().step_by(stride).take(size);
+    let outside = ids[outside..].iter().step_by(stride).take(size);
+
+    trace!(
+        "{} inside: {:?}, outside: {:?}",
+        side,
+        inside.clone().collect::<Vec<_>>(),
+        outside.clone().collect::<Vec<_>>()
+    );
+
+    inside.zip(outside).any(|(a, b)| b.is_some() && a == b)
+}
+
+// The id of a pumpkin with its origin at (x, y). Ids are `y * width + x + 1`, which needs 32 bits
+// once a grid has more than `u16::MAX` cells.
+pub(crate) fn pumpkin_id(x: u16, y: u16, width: u16) -> NonZeroU32 {
+    NonZeroU32::new(y as u32 * width as u32 + x as u32 + 1).unwrap()
+}
+
+// Width of the id columns printed by `Display`, the digits of the largest id but at least 3
+pub(crate) fn id_column_width(ids: &[Option<NonZeroU32>]) -> usize {
+    let max = ids.iter().flatten().max().map_or(0, |id| id.get());
+    max.checked_ilog10()
+        .map_or(1, |digits| digits as usize + 1)
+        .max(3)
+}
+
+/// Step by step insertion into a [`PumpkinPatch`], see [`PumpkinPatch::inserter`]
+pub struct Inserter<'a, P> {
+    patch: &'a mut PumpkinPatch<P>,
+    order: alloc::vec::IntoIter<(u16, u16)>,
+}
+
+impl<P: Deref<Target = LookupTable>> Iterator for Inserter<'_, P> {
+    type Item = Square;
+
+    fn next(&mut self) -> Option<Square> {
+        self.order.find_map(|(x, y)| self.patch.add(x, y).ok())
+    }
+}
+
+/// Patches are equal when they have the same dimensions, filled cells and ids. The lookup
+/// table, the size limits, the tie break, the undo history and the search buffers aren't
+/// compared.
+impl<P> PartialEq for PumpkinPatch<P> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.width, self.height) == (other.width, other.height)
+            && self.bitmap == other.bitmap
+            && self.ids == other.ids
+    }
+}
+
+impl<P> Eq for PumpkinPatch<P> {}
+
+impl<P: Deref<Target = LookupTable>> core::fmt::Display for PumpkinPatch<P> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let width = self.display_width();
+
+        // Print the ids in a grid, but reverse the order of the y direction
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                let id = self.ids[self.index(x, y)].map_or(0, |id| id.get());
+                write!(f, "{:width$} ", id)?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<P: Deref<Target = LookupTable>> PumpkinPatch<P> {
+    /// Draws the patch as a PNG with every cell `cell_px` pixels wide
+    ///
+    /// Each pumpkin gets a color derived from its id and is outlined in black, empty cells are
+    /// left in a dark background color. Like [`Display`](core::fmt::Display) the bottom row of
+    /// the image is `y = 0`.
+    #[cfg(feature = "image")]
+    pub fn render_png(&self, path: &std::path::Path, cell_px: u32) -> image::ImageResult<()> {
+        self.render_image(cell_px)
+            .save_with_format(path, image::ImageFormat::Png)
+    }
+
+    #[cfg(feature = "image")]
+    fn render_image(&self, cell_px: u32) -> image::RgbImage {
+        const BACKGROUND: image::Rgb<u8> = image::Rgb([40, 40, 40]);
+        const BORDER: image::Rgb<u8> = image::Rgb([0, 0, 0]);
+
+        let (width, height) = (self.width as u32, self.height as u32);
+        image::RgbImage::from_fn(width * cell_px, height * cell_px, |px, py| {
+            // image rows go down, grid rows go up
+            let (x, y) = (px / cell_px, height - 1 - py / cell_px);
+            let (dx, dy) = (px % cell_px, py % cell_px);
+
+            let Some(id) = self.get(x as u16, y as u16) else {
+                return BACKGROUND;
+            };
+            let other = |nx: u32, ny: u32| {
+                nx >= width || ny >= height || self.get(nx as u16, ny as u16) != Some(id)
+            };
+            let border = (dx == 0 && (x == 0 || other(x - 1, y)))
+                || (dx == cell_px - 1 && other(x + 1, y))
+                || (dy == 0 && other(x, y + 1))
+                || (dy == cell_px - 1 && (y == 0 || other(x, y - 1)));
+
+            if border {
+                BORDER
+            } else {
+                image::Rgb(pumpkin_color(id))
+            }
+        })
+    }
+
+    /// Lists the pumpkins as CSV with the columns `id,x,y,size,cells`, sorted by id
+    pub fn to_csv(&self) -> String {
+        use core::fmt::Write;
+
+        let mut csv = String::from("id,x,y,size,cells\n");
+        // `pumpkins` visits the origins in row-major order, which is also id order
+        for (id, sq) in self.pumpkins() {
+            writeln!(
+                csv,
+                "{},{},{},{},{}",
+                id,
+                sq.x(),
+                sq.y(),
+                sq.size(),
+                sq.cell_count()
+            )
+            .unwrap();
+        }
+
+        csv
+    }
+
+    /// Writes the grid and its pumpkins as JSON
+    ///
+    /// The output is `{"size", "cells", "pumpkins"}`, rectangular grids have `"width"` and
+    /// `"height"` in place of `"size"`. `cells` holds one array of ids per row, top row first
+    /// like [`Display`](core::fmt::Display), with `0` for empty cells. `pumpkins` lists
+    /// `{"id", "x", "y", "size"}` for every pumpkin in id order.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        let cells = self.to_grid();
+        let pumpkins: Vec<_> = self
+            .pumpkins()
+            .map(|(id, sq)| {
+                serde_json::json!({ "id": id, "x": sq.x(), "y": sq.y(), "size": sq.size() })
+            })
+            .collect();
+
+        let mut json = serde_json::json!({ "cells": cells, "pumpkins": pumpkins });
+        if self.width == self.height {
+            json["size"] = self.width.into();
+        } else {
+            json["width"] = self.width.into();
+            json["height"] = self.height.into();
+        }
+        json.to_string()
+    }
+
+    /// Encodes the ids in row-major order as space separated `count:id` runs, `0` standing for
+    /// empty cells
+    ///
+    /// A 10x10 patch with a single filled corner is `1:1 99:0`. Read it back with
+    /// [`PumpkinPatch::from_rle`].
+    pub fn to_rle(&self) -> String {
+        use core::fmt::Write;
+
+        let mut rle = String::new();
+        let mut cells = self.ids.iter().map(|id| id.map_or(0, NonZeroU32::get));
+        let Some(mut current) = cells.next() else {
+            return rle;
+        };
+        let mut count = 1;
+        for id in cells.chain([u32::MAX]) {
+            // `u32::MAX` is past every id, it flushes the last run
+            if id == current {
+                count += 1;
+                continue;
+            }
+            if !rle.is_empty() {
+                rle.push(' ');
+            }
+            write!(rle, "{}:{}", count, current).unwrap();
+            (current, count) = (id, 1);
+        }
+
+        rle
+    }
+
+    /// Draws the patch as an SVG with one labeled `<rect>` per pumpkin
+    ///
+    /// Cells are 20 units wide and `y = 0` is the bottom row. Pumpkins that wrap around a
+    /// toroidal grid are drawn from their origin and clipped at the far edges.
+    pub fn to_svg(&self) -> String {
+        use core::fmt::Write;
+
+        const CELL: u32 = 20;
+
+        let (width, height) = (self.width as u32 * CELL, self.height as u32 * CELL);
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n",
+            width, height
+        );
+
+        for (id, sq) in self.pumpkins() {
+            let size = sq.size() as u32 * CELL;
+            let x = sq.x() as u32 * CELL;
+            // svg rows go down, grid rows go up. Wrapping pumpkins can start above the image.
+            let y = height as i64 - (sq.y() as u32 * CELL + size) as i64;
+            let [r, g, b] = pumpkin_color(id);
+
+            writeln!(
+                svg,
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#{:02x}{:02x}{:02x}\" stroke=\"black\"/>",
+                x, y, size, size, r, g, b
+            )
+            .unwrap();
+            writeln!(
+                svg,
+                "<text x=\"{}\" y=\"{}\" font-size=\"{}\" text-anchor=\"middle\" dominant-baseline=\"central\">{}</text>",
+                x + size / 2,
+                y + size as i64 / 2,
+                size / 2,
+                id
+            )
+            .unwrap();
+        }
+
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    /// Draws the filled cells as `#` and the empty cells as `.`, one line per row starting with
+    /// the top row like [`Display`](core::fmt::Display)
+    pub fn render_mask(&self) -> String {
+        let mut out = String::with_capacity((self.width as usize + 1) * self.height as usize);
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                out.push(if self.contains(x, y) { '#' } else { '.' });
+            }
+            out.push('\n');
+        }
+
+        out
+    }
+
+    /// Draws the patch for a terminal, each cell is two spaces with a background color picked
+    /// from the basic ANSI colors by its id
+    ///
+    /// Empty cells are left blank. Rows are printed top down like
+    /// [`Display`](core::fmt::Display).
+    pub fn render_ansi(&self) -> String {
+        // The normal and bright ANSI background colors without the blacks, grays and whites
+        const PALETTE: [u8; 12] = [41, 42, 43, 44, 45, 46, 101, 102, 103, 104, 105, 106];
+
+        let mut out = String::new();
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                match self.get(x, y) {
+                    Some(id) => {
+                        let color = PALETTE[scramble(id) as usize % PALETTE.len()];
+                        out.push_str(&format!("\x1b[{}m  \x1b[0m", color));
+                    }
+                    None => out.push_str("  "),
+                }
+            }
+            out.push('\n');
+        }
+
+        out
+    }
+}
+
+// Scrambles a pumpkin id so neighboring pumpkins, which often have consecutive ids, get unrelated
+// colors
+fn scramble(id: NonZeroU32) -> u32 {
+    id.get().wrapping_mul(0x9e37_79b9)
+}
+
+// A stable color for each pumpkin id, kept light so black outlines and text stand out
+fn pumpkin_color(id: NonZeroU32) -> [u8; 3] {
+    let [r, g, b, _] = scramble(id).to_be_bytes();
+    [r / 2 + 128, g / 2 + 128, b / 2 + 128]
+}
+
+// The serialized form of a `PumpkinPatch`. The lookup table isn't included.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct PatchStateRef<'a> {
+    width: u16,
+    height: u16,
+    #[serde(with = "bitmap_serde")]
+    bitmap: &'a Bitmap,
+    ids: &'a [Option<NonZeroU32>],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_square_size: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_square_size: Option<u16>,
+    #[serde(skip_serializing_if = "is_first_found")]
+    tie_break: TieBreak,
+}
+
+#[cfg(feature = "serde")]
+fn is_first_found(tie_break: &TieBreak) -> bool {
+    *tie_break == TieBreak::FirstFound
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct PatchState {
+    width: u16,
+    height: u16,
+    #[serde(with = "bitmap_serde")]
+    bitmap: Bitmap,
+    ids: Vec<Option<NonZeroU32>>,
+    #[serde(default)]
+    max_square_size: Option<u16>,
+    #[serde(default)]
+    min_square_size: Option<u16>,
+    #[serde(default)]
+    tie_break: TieBreak,
+}
+
+/// Writes the grid, its filled cells and the pumpkin ids. Use
+/// [`PumpkinPatch::deserialize_with`] to read it back.
+#[cfg(feature = "serde")]
+impl<P> serde::Serialize for PumpkinPatch<P> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        PatchStateRef {
+            width: self.width,
+            height: self.height,
+            bitmap: &self.bitmap,
+            ids: &self.ids,
+            max_square_size: self.max_square_size,
+            min_square_size: self.min_square_size,
+            tie_break: self.tie_break,
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<P: Deref<Target = LookupTable>> PumpkinPatch<P> {
+    /// Reads a patch written by its `Serialize` implementation
+    ///
+    /// The lookup table isn't part of the serialized data, `lookup_table` has to be built for
+    /// the same grid. Data whose vectors don't match the grid, or whose ids don't agree with the
+    /// filled cells, is rejected.
+    pub fn deserialize_with<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+        lookup_table: P,
+    ) -> Result<Self, D::Error> {
+        use serde::{de::Error, Deserialize};
+
+        let PatchState {
+            width,
+            height,
+            bitmap,
+            ids,
+            max_square_size,
+            min_square_size,
+            tie_break,
+        } = PatchState::deserialize(deserializer)?;
+
+        if (lookup_table.width(), lookup_table.height()) != (width, height) {
+            return Err(D::Error::custom(format!(
+                "lookup table is {}x{}, the patch is {}x{}",
+                lookup_table.width(),
+                lookup_table.height(),
+                width,
+                height
+            )));
+        }
+        let cells = width as usize * height as usize;
+        if bitmap.len() != cells || ids.len() != cells {
+            return Err(D::Error::custom(format!(
+                "expected {} cells, found {} bits and {} ids",
+                cells,
+                bitmap.len(),
+                ids.len()
+            )));
+        }
+        for (idx, id) in ids.iter().enumerate() {
+            let deferred = id.is_none() && min_square_size.is_some();
+            if id.is_some() != bitmap[idx] && !deferred {
+                return Err(D::Error::custom(format!(
+                    "id of cell {} doesn't match the bitmap",
+                    idx
+                )));
+            }
+            // every pumpkin has to cover its origin, `pumpkin_square` relies on it
+            if let Some(id) = id {
+                if ids.get(id.get() as usize - 1) != Some(&Some(*id)) {
+                    return Err(D::Error::custom(format!(
+                        "pumpkin {} doesn't cover its origin",
+                        id
+                    )));
+                }
+            }
+        }
+
+        if max_square_size == Some(0) || min_square_size == Some(0) {
+            return Err(D::Error::custom("square size limits have to be at least 1"));
+        }
+
+        let mut patch = Self::new_rect(width, height, lookup_table);
+        patch.filled = bitmap.count_ones();
+        patch.bitmap = bitmap;
+        patch.ids = ids;
+        patch.max_square_size = max_square_size;
+        patch.min_square_size = min_square_size;
+        patch.tie_break = tie_break;
+        // the checks above keep `validate` from indexing out of bounds, it catches the rest,
+        // like ids that don't form squares
+        patch.validate().map_err(D::Error::custom)?;
+
+        Ok(patch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use rand::seq::SliceRandom;
+
+    #[test]
+    fn test_bit_sq() {
+        let bit_sq = Square::new(0, 0, 3);
+        // (1, 1) and (2, 2) should be contained
+        assert!(bit_sq.contains(1, 1), "bit_sq should contain (1, 1)");
+        assert!(bit_sq.contains(2, 2), "bit_sq should contain (2, 2)");
+        // (0, 3) should not be contained
+        assert!(!bit_sq.contains(0, 3), "bit_sq should not contain (0, 3)");
+        // (2, 1)
+        assert!(bit_sq.contains(2, 1), "bit_sq should contain (2, 1)");
+
+        // Try another square
+        let bit_sq = Square::new(5, 5, 5);
+        // (5, 5) and (9, 9) should be contained
+        assert!(bit_sq.contains(5, 5), "bit_sq should contain (5, 5)");
+        assert!(bit_sq.contains(9, 9), "bit_sq should contain (9, 9)");
+    }
+
+    #[test]
+    fn test_try_new() {
+        assert_eq!(Square::try_new(0, 0, 0), None);
+        assert_eq!(Square::try_new(0, 0, 1), Some(Square::new(0, 0, 1)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_square_serde() {
+        let sq = Square::new(2, 5, 3);
+        let json = serde_json::to_string(&sq).unwrap();
+        assert_eq!(json, r#"{"x":2,"y":5,"size":3}"#);
+        assert_eq!(serde_json::from_str::<Square>(&json).unwrap(), sq);
+
+        let err = serde_json::from_str::<Square>(r#"{"x":2,"y":5,"size":0}"#).unwrap_err();
+        assert!(err.to_string().contains("square size must be non-zero"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_patch_serde() {
+        let table = Rc::new(LookupTable::new(5));
+        let mut pumpkins = PumpkinPatch::new(5, table.clone());
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1), (4, 4), (3, 2), (2, 3)] {
+            pumpkins.add(x, y).unwrap();
+        }
+
+        let json = serde_json::to_string(&pumpkins).unwrap();
+        let read = |json: &str, size: u16| {
+            let mut deserializer = serde_json::Deserializer::from_str(json);
+            PumpkinPatch::deserialize_with(&mut deserializer, Rc::new(LookupTable::new(size)))
+                .map_err(|e| e.to_string())
+        };
+
+        let mut restored = read(&json, 5).unwrap();
+        assert_eq!(restored.bitmap, pumpkins.bitmap);
+        assert_eq!(restored.ids, pumpkins.ids);
+        assert_eq!(restored.filled_cells(), 7);
+        assert_eq!(restored.get_square_at(1, 1), Some(Square::new(0, 0, 2)));
+
+        // the restored patch keeps merging like the original
+        for (x, y) in [(2, 0), (2, 1), (2, 2), (0, 2), (1, 2)] {
+            assert_eq!(restored.add(x, y), pumpkins.add(x, y));
+        }
+        assert_eq!(restored.ids, pumpkins.ids);
+
+        // a table for a different grid is rejected
+        assert!(read(&json, 4).unwrap_err().contains("lookup table is 4x4"));
+
+        // the size cap is kept, patches without one don't write it
+        assert!(!json.contains("max_square_size"));
+        let capped = PumpkinPatch::new(5, table.clone()).with_max_square_size(3);
+        let json = serde_json::to_string(&capped).unwrap();
+        assert_eq!(read(&json, 5).unwrap().max_square_size(), Some(3));
+        assert!(!json.contains("tie_break"));
+        let lowest = PumpkinPatch::new(5, table.clone()).with_tie_break(TieBreak::LowestXY);
+        let json = serde_json::to_string(&lowest).unwrap();
+        assert_eq!(read(&json, 5).unwrap().tie_break(), TieBreak::LowestXY);
+
+        // so are ids that don't match the grid or the filled cells
+        let json = r#"{"width":2,"height":2,"bitmap":[4,[128]],"ids":[1,null,null]}"#;
+        assert!(read(json, 2).unwrap_err().contains("expected 4 cells"));
+        let json = r#"{"width":2,"height":2,"bitmap":[4,[128]],"ids":[null,1,null,null]}"#;
+        assert!(read(json, 2)
+            .unwrap_err()
+            .contains("doesn't match the bitmap"));
+
+        // as are ids that don't form squares
+        let json = r#"{"width":2,"height":2,"bitmap":[4,[192]],"ids":[1,1,null,null]}"#;
+        assert!(read(json, 2)
+            .unwrap_err()
+            .contains("cell (0, 1) is inside pumpkin 1 but has id None"));
+    }
+
+    #[test]
+    fn test_square_accessors() {
+        let sq = Square::new(2, 2, 3);
+        assert_eq!((sq.x(), sq.y(), sq.size()), (2, 2, 3));
+        assert_eq!(sq.origin(), (2, 2));
+        assert_eq!(sq.corners(), [(2, 2), (4, 2), (2, 4), (4, 4)]);
+
+        // a size-1 square has all four corners on the same cell
+        assert_eq!(Square::new(7, 1, 1).corners(), [(7, 1); 4]);
+    }
+
+    #[test]
+    fn test_iter_cells() {
+        for sq in [
+            Square::new(0, 0, 1),
+            Square::new(3, 1, 2),
+            Square::new(2, 5, 4),
+        ] {
+            let cells: Vec<_> = sq.iter_cells().collect();
+            assert_eq!(cells.len() as u32, sq.cell_count());
+            assert!(cells.iter().all(|&(x, y)| sq.contains(x, y)));
+        }
+
+        let cells: Vec<_> = Square::new(3, 1, 2).iter_cells().collect();
+        assert_eq!(cells, [(3, 1), (4, 1), (3, 2), (4, 2)]);
+        assert_eq!(
+            Square::new(4, 4, 1).iter_cells().collect::<Vec<_>>(),
+            [(4, 4)]
+        );
+    }
+
+    #[test]
+    fn test_contains_square() {
+        let sq = Square::new(1, 1, 3);
+        assert!(sq.contains_square(&sq));
+        assert!(sq.contains_square(&Square::new(2, 2, 2)));
+        assert!(!sq.contains_square(&Square::new(2, 2, 3)));
+        assert!(!sq.contains_square(&Square::new(0, 1, 2)));
+        assert!(!Square::new(2, 2, 2).contains_square(&sq));
+
+        // every larger square must fully enclose the square it was grown from
+        for grid_size in 1..=6 {
+            for idx in 0..(grid_size * grid_size * grid_size) as usize {
+                let sq = Square::from_index(idx, grid_size as usize, grid_size as usize);
+                for larger in sq.next_larger_squares(grid_size, grid_size) {
+                    assert!(larger.contains_square(&sq), "{:?} ⊄ {:?}", sq, larger);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_next_larger_squares_brute_force() {
+        // every square one larger that fits in the grid and contains the source, found by
+        // trying all origins
+        let brute_force = |sq: Square, width: u16, height: u16| {
+            let mut squares: Vec<_> = (0..height)
+                .flat_map(|y| (0..width).map(move |x| Square::new(x, y, sq.size() + 1)))
+                .filter(|larger| larger.fits(width, height) && larger.contains_square(&sq))
+                .collect();
+            squares.sort_by_key(|larger| (larger.x, larger.y));
+            squares
+        };
+
+        let grids = (1..=8)
+            .flat_map(|width| (1..=8).map(move |height| (width, height)))
+            .filter(|&(width, height)| width == height || width.max(height) <= 6);
+        for (width, height) in grids {
+            for y in 0..height {
+                for x in 0..width {
+                    for size in 1..=width.min(height) {
+                        let sq = Square::new(x, y, size);
+                        if !sq.fits(width, height) {
+                            continue;
+                        }
+                        let mut larger = sq.next_larger_squares(width, height);
+                        larger.sort_by_key(|larger| (larger.x, larger.y));
+                        assert_eq!(
+                            larger,
+                            brute_force(sq, width, height),
+                            "{:?} in {}x{}",
+                            sq,
+                            width,
+                            height
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_translate() {
+        let sq = Square::new(0, 0, 2);
+        assert_eq!(sq.translate(-1, 0, 5), None);
+        assert_eq!(sq.translate(0, -1, 5), None);
+        assert_eq!(sq.translate(1, 1, 5), Some(Square::new(1, 1, 2)));
+        assert_eq!(sq.translate(3, 3, 5), Some(Square::new(3, 3, 2)));
+        assert_eq!(sq.translate(4, 0, 5), None);
+        assert_eq!(sq.translate(1, 1, 2), None);
+
+        let sq = Square::new(3, 3, 2);
+        assert_eq!(sq.translate(-3, -2, 5), Some(Square::new(0, 1, 2)));
+        assert_eq!(sq.translate(i32::MIN, 0, 5), None);
+        assert_eq!(sq.translate(0, i32::MAX, 5), None);
+    }
+
+    #[test]
+    fn test_same_size_neighbors() {
+        // corner square
+        let neighbors = Square::new(0, 0, 2).next_same_size_neighbors(5, 5);
+        assert_eq!(neighbors, [Square::new(1, 0, 2), Square::new(0, 1, 2)]);
+
+        // central square
+        let neighbors = Square::new(1, 1, 2).next_same_size_neighbors(5, 5);
+        assert_eq!(
+            neighbors,
+            [
+                Square::new(0, 1, 2),
+                Square::new(2, 1, 2),
+                Square::new(1, 0, 2),
+                Square::new(1, 2, 2),
+            ]
+        );
+
+        // flush against the far wall
+        let neighbors = Square::new(3, 1, 2).next_same_size_neighbors(5, 5);
+        assert_eq!(neighbors.len(), 3);
+
+        // a grid sized square can't move
+        assert!(Square::new(0, 0, 5)
+            .next_same_size_neighbors(5, 5)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_perimeter_cells() {
+        assert_eq!(Square::new(3, 4, 1).perimeter_cells(), [(3, 4)]);
+        assert_eq!(
+            Square::new(0, 0, 2).perimeter_cells(),
+            [(0, 0), (0, 1), (1, 1), (1, 0)]
+        );
+        assert_eq!(
+            Square::new(1, 1, 3).perimeter_cells(),
+            [
+                (1, 1),
+                (1, 2),
+                (1, 3),
+                (2, 3),
+                (3, 3),
+                (3, 2),
+                (3, 1),
+                (2, 1)
+            ]
+        );
+
+        for size in 1..=8 {
+            let sq = Square::new(2, 3, size);
+            let cells = sq.perimeter_cells();
+            let expected = if size == 1 { 1 } else { 4 * size as usize - 4 };
+            assert_eq!(cells.len(), expected);
+
+            let unique: std::collections::HashSet<_> = cells.iter().collect();
+            assert_eq!(unique.len(), cells.len(), "duplicated perimeter cell");
+            assert!(cells.iter().all(|&(x, y)| sq.contains(x, y)));
+        }
+    }
+
+    #[test]
+    fn test_bitmap_into() {
+        // one buffer reused across squares and grid shapes, left dirty in between
+        let mut scratch = Bitmap::new(200);
+        scratch.set(150, true);
+        for (width, height) in [(5, 5), (7, 3), (1, 1), (12, 9)] {
+            let (w, h) = (width as usize, height as usize);
+            for idx in 0..w * h * w.min(h) {
+                let square = Square::from_index(idx, w, h);
+                square.bitmap_into(width, height, &mut scratch);
+                assert_eq!(scratch, square.bitmap(width, height));
+
+                square.bitmap_toroidal_into(width, height, &mut scratch);
+                assert_eq!(scratch, square.bitmap_toroidal(width, height));
+            }
+        }
+    }
+
+    #[test]
+    fn test_square_geometry() {
+        // disjoint
+        let a = Square::new(0, 0, 2);
+        let b = Square::new(5, 5, 2);
+        assert!(!a.overlaps(&b));
+        assert_eq!(a.intersection(&b), None);
+        assert_eq!(a.bounding_union(&b), (0, 0, 7, 7));
+
+        // touching along an edge
+        let b = Square::new(2, 0, 2);
+        assert!(!a.overlaps(&b));
+        assert_eq!(a.intersection(&b), None);
+        assert_eq!(a.bounding_union(&b), (0, 0, 4, 2));
+
+        // nested
+        let outer = Square::new(1, 1, 4);
+        let inner = Square::new(2, 3, 2);
+        assert!(outer.overlaps(&inner));
+        assert_eq!(outer.intersection(&inner), Some((2, 3, 2, 2)));
+        assert_eq!(outer.bounding_union(&inner), (1, 1, 4, 4));
+
+        // partially overlapping
+        let a = Square::new(0, 0, 3);
+        let b = Square::new(2, 1, 3);
+        assert!(a.overlaps(&b));
+        assert!(b.overlaps(&a));
+        assert_eq!(a.intersection(&b), Some((2, 1, 1, 2)));
+        assert_eq!(a.bounding_union(&b), (0, 0, 5, 4));
+
+        // identical
+        assert_eq!(a.intersection(&a), Some((0, 0, 3, 3)));
+        assert_eq!(a.bounding_union(&a), (0, 0, 3, 3));
+    }
+
+    #[test]
+    fn test_pumpkins_merge_2() {
+        let mut pumpkins = PumpkinPatch::new_make_table(2);
+        let order: &[(u16, u16)] = &[(0, 0), (0, 1), (1, 0), (1, 1)];
+
         let mut iter = order.iter().cloned();
 
-        for _ in 0..3 {
-            let (x, y) = iter.next().unwrap();
-            let sq = pumpkins.add(x, y);
-            println!("{}", pumpkins);
-            assert!(sq.size.get() == 1, "Square should be size 1");
-            assert!(
-                (sq.x, sq.y) == (x, y),
-                "Square should be at the correct position"
-            );
+        for _ in 0..3 {
+            let (x, y) = iter.next().unwrap();
+            let sq = pumpkins.add(x, y).unwrap();
+            println!("{}", pumpkins);
+            assert_eq!(pumpkins.validate(), Ok(()));
+            assert!(sq.size.get() == 1, "Square should be size 1");
+            assert!(
+                (sq.x, sq.y) == (x, y),
+                "Square should be at the correct position"
+            );
+        }
+
+        // the last one should merge with the first 3
+        let sq = pumpkins.add(1, 1).unwrap();
+        println!("{}", pumpkins);
+        assert_eq!(pumpkins.validate(), Ok(()));
+        assert!(sq.size.get() == 2, "Square should be size 2");
+        assert!(
+            (sq.x, sq.y) == (0, 0),
+            "Square should be at the correct position"
+        );
+    }
+
+    #[test]
+    fn test_add_with_hook_merge_2() {
+        // the order of `test_pumpkins_merge_2`
+        let mut pumpkins = PumpkinPatch::new_make_table(2);
+        for (x, y) in [(0, 0), (0, 1), (1, 0)] {
+            let sq = pumpkins
+                .add_with_hook(x, y, |event| panic!("unexpected merge {:?}", event))
+                .unwrap();
+            assert_eq!(sq, Square::new(x, y, 1));
+        }
+
+        let mut events = Vec::new();
+        let sq = pumpkins
+            .add_with_hook(1, 1, |event| events.push(event))
+            .unwrap();
+        assert_eq!(sq, Square::new(0, 0, 2));
+        assert_eq!(
+            events,
+            [MergeEvent {
+                square: sq,
+                absorbed: [1, 2, 3].map(|id| NonZeroU32::new(id).unwrap()).to_vec(),
+            }]
+        );
+        assert_eq!(pumpkins.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_add_tracked_merge_2() {
+        // the order of `test_pumpkins_merge_2`
+        let mut tracked = PumpkinPatch::new_make_table(2);
+        let mut plain = PumpkinPatch::new_make_table(2);
+        for (x, y) in [(0, 0), (0, 1), (1, 0)] {
+            assert_eq!(
+                tracked.add_tracked(x, y),
+                Ok((Square::new(x, y, 1), vec![]))
+            );
+            plain.add(x, y).unwrap();
+        }
+
+        assert_eq!(
+            tracked.add_tracked(1, 1),
+            Ok((Square::new(0, 0, 2), vec![(0, 0), (1, 0), (0, 1)]))
+        );
+        plain.add(1, 1).unwrap();
+        assert_eq!(tracked, plain);
+        assert_eq!(tracked.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_add_errors() {
+        let mut pumpkins = PumpkinPatch::new_make_table(3);
+        assert_eq!(pumpkins.add(0, 0), Ok(Square::new(0, 0, 1)));
+        assert_eq!(pumpkins.add(0, 0), Err(AddError::AlreadyFilled));
+        assert_eq!(pumpkins.add(3, 0), Err(AddError::OutOfBounds));
+        assert_eq!(pumpkins.add(0, 3), Err(AddError::OutOfBounds));
+        assert_eq!(pumpkins.filled_cells(), 1);
+    }
+
+    #[test]
+    fn test_preview() {
+        for size in 2..=6 {
+            let mut pumpkins = PumpkinPatch::new_make_table(size);
+            let mut order = (0..size * size).collect::<Vec<_>>();
+            order.shuffle(&mut rand::thread_rng());
+
+            for idx in order {
+                let (x, y) = (idx % size, idx / size);
+                let before = pumpkins.clone();
+                let preview = pumpkins.preview(x, y).unwrap();
+                assert_eq!(pumpkins.bitmap, before.bitmap);
+                assert_eq!(pumpkins.ids, before.ids);
+
+                assert_eq!(Ok(preview), pumpkins.add(x, y));
+                assert_eq!(pumpkins.preview(x, y), Err(AddError::AlreadyFilled));
+            }
+        }
+    }
+
+    #[test]
+    fn test_seeded_fill() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let fill = |seed: u64| {
+            let size = 12;
+            let mut order: Vec<_> = (0..size * size).map(|i| (i % size, i / size)).collect();
+            order.shuffle(&mut StdRng::seed_from_u64(seed));
+            PumpkinPatch::new_make_table(size)
+                .inserter(order)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(fill(7), fill(7));
+        assert_ne!(fill(7), fill(8));
+    }
+
+    #[test]
+    fn test_reused_search() {
+        for size in 2..=8 {
+            let mut pumpkins = PumpkinPatch::new_make_table(size);
+            let mut order = (0..size * size).collect::<Vec<_>>();
+            order.shuffle(&mut rand::thread_rng());
+
+            for idx in order {
+                let (x, y) = (idx % size, idx / size);
+                // a freshly allocated search is what every add used to do
+                let fresh = pumpkins.largest_square(x, y, &mut Search::default());
+                assert_eq!(Ok(fresh), pumpkins.add(x, y));
+
+                let search = &pumpkins.search;
+                assert_eq!(search.visited.len(), pumpkins.lookup_table.num_squares());
+                assert!(search.visited.none());
+                assert!(search.touched.is_empty() && search.stack.is_empty());
+            }
+            assert_eq!(pumpkins.pumpkin_count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_search_stops_at_grid_size() {
+        let size = 6;
+        let (cx, cy) = (2, 3);
+        let mut pumpkins = PumpkinPatch::new_make_table(size);
+        for (x, y) in (0..size).flat_map(|y| (0..size).map(move |x| (x, y))) {
+            if (x, y) != (cx, cy) {
+                pumpkins.add(x, y).unwrap();
+            }
+        }
+
+        // Every square holding the last cell passes the bitmap check, a search that doesn't
+        // stop early visits all of them
+        let containing = (1..=size)
+            .flat_map(|s| (0..=size - s).flat_map(move |y| (0..=size - s).map(move |x| (x, y, s))))
+            .filter(|&(x, y, s)| Square::new(x, y, s).contains(cx, cy))
+            .count();
+
+        let (square, stats) = pumpkins.add_with_stats(cx, cy).unwrap();
+        assert_eq!(square, Square::new(0, 0, size));
+        assert!(stats.squares_visited < containing);
+        assert!(pumpkins.search.stack.is_empty() && pumpkins.search.visited.none());
+    }
+
+    #[test]
+    fn test_max_square_size() {
+        let mut pumpkins = PumpkinPatch::new_make_table(4).with_max_square_size(2);
+        assert_eq!(pumpkins.max_square_size(), Some(2));
+        for (x, y) in (0..4).flat_map(|y| (0..4).map(move |x| (x, y))) {
+            assert!(pumpkins.add(x, y).unwrap().size() <= 2);
+        }
+        assert_eq!(pumpkins.validate(), Ok(()));
+
+        let mut squares: Vec<_> = pumpkins.pumpkins().map(|(_, sq)| sq).collect();
+        squares.sort_by_key(|sq| (sq.y(), sq.x()));
+        assert_eq!(
+            squares,
+            [(0, 0), (2, 0), (0, 2), (2, 2)].map(|(x, y)| Square::new(x, y, 2))
+        );
+
+        // re-merging after a remove respects the cap too
+        pumpkins.remove(0, 0);
+        pumpkins.add(0, 0).unwrap();
+        assert_eq!(pumpkins.pumpkin_count(), 4);
+
+        // a cap at least as large as the grid changes nothing
+        let mut uncapped = PumpkinPatch::new_make_table(4).with_max_square_size(9);
+        for (x, y) in (0..4).flat_map(|y| (0..4).map(move |x| (x, y))) {
+            uncapped.add(x, y).unwrap();
+        }
+        assert_eq!(uncapped.pumpkin_count(), 1);
+
+        let mut bad = PumpkinPatch::new_make_table(4);
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            bad.add(x, y).unwrap();
+        }
+        bad.max_square_size = Some(1);
+        assert!(bad
+            .validate()
+            .unwrap_err()
+            .contains("larger than the max size 1"));
+    }
+
+    #[test]
+    #[should_panic(expected = "at least 1x1")]
+    fn test_max_square_size_zero() {
+        PumpkinPatch::new_make_table(4).with_max_square_size(0);
+    }
+
+    #[test]
+    fn test_min_square_size() {
+        let mut pumpkins = PumpkinPatch::new_make_table(2).with_min_square_size(2);
+        assert_eq!(pumpkins.min_square_size(), Some(2));
+
+        // the first three cells can't form a 2x2 yet, they are filled but unassigned
+        for (x, y) in [(0, 0), (1, 0), (0, 1)] {
+            assert_eq!(pumpkins.preview(x, y), Err(AddError::Deferred));
+            assert_eq!(pumpkins.add(x, y), Err(AddError::Deferred));
+            assert!(pumpkins.contains(x, y));
+            assert_eq!(pumpkins.get(x, y), None);
+            assert_eq!(pumpkins.validate(), Ok(()));
+        }
+        assert_eq!(pumpkins.add(0, 0), Err(AddError::AlreadyFilled));
+        assert_eq!(pumpkins.filled_cells(), 3);
+        assert_eq!(pumpkins.pumpkin_count(), 0);
+
+        // the last cell completes the square and assigns all four
+        assert_eq!(pumpkins.add(1, 1), Ok(Square::new(0, 0, 2)));
+        assert_eq!(pumpkins.pumpkin_count(), 1);
+        assert_eq!(pumpkins.validate(), Ok(()));
+
+        // removing a cell leaves the others deferred again
+        pumpkins.remove(1, 1);
+        assert_eq!(pumpkins.pumpkin_count(), 0);
+        assert_eq!(pumpkins.filled_cells(), 3);
+        pumpkins.remove(0, 0);
+        assert!(!pumpkins.contains(0, 0));
+        assert_eq!(pumpkins.filled_cells(), 2);
+        assert_eq!(pumpkins.validate(), Ok(()));
+
+        // without a minimum an unassigned filled cell is still invalid
+        pumpkins.min_square_size = None;
+        assert!(pumpkins.validate().unwrap_err().contains("has id None"));
+    }
+
+    #[test]
+    #[should_panic(expected = "larger than the max")]
+    fn test_min_above_max_square_size() {
+        PumpkinPatch::new_make_table(4)
+            .with_max_square_size(2)
+            .with_min_square_size(3);
+    }
+
+    #[test]
+    fn test_tie_break() {
+        // (1, 0) completes both the 2x2 square at (0, 0) and the one at (1, 0)
+        let table = Rc::new(LookupTable::new_rect(3, 2));
+        let setup = |tie_break| {
+            let mut pumpkins = PumpkinPatch::builder()
+                .lookup_table(table.clone())
+                .tie_break(tie_break)
+                .build()
+                .unwrap();
+            for (x, y) in [(0, 0), (0, 1), (2, 0), (2, 1), (1, 1)] {
+                assert_eq!(pumpkins.add(x, y).unwrap().size(), 1);
+            }
+            pumpkins
+        };
+        assert_eq!(
+            setup(TieBreak::FirstFound).candidates(1, 0)[1..],
+            [Square::new(0, 0, 2), Square::new(1, 0, 2)]
+        );
+
+        let first = setup(TieBreak::FirstFound).add(1, 0).unwrap();
+        assert_eq!(first, Square::new(1, 0, 2));
+        assert_eq!(
+            setup(TieBreak::LowestXY).add(1, 0),
+            Ok(Square::new(0, 0, 2))
+        );
+        assert_eq!(
+            setup(TieBreak::HighestXY).add(1, 0),
+            Ok(Square::new(1, 0, 2))
+        );
+
+        // (1, 2) completes three size-3 squares, each policy picks a different one
+        //  16  16  23  19  19
+        //  16  16  18  19  19
+        //  11   .  13  14   .
+        //   6   2   2   9   .
+        //   1   2   2   4   .
+        let rle =
+            "1:1 2:2 1:4 1:0 1:6 2:2 1:9 1:0 1:11 1:0 1:13 1:14 1:0 2:16 1:18 2:19 2:16 1:23 2:19";
+        let pumpkins = PumpkinPatch::from_rle(rle, 5, Rc::new(LookupTable::new(5))).unwrap();
+        let sizes: Vec<_> = pumpkins.candidates(1, 2).iter().map(Square::size).collect();
+        assert_eq!(sizes.iter().filter(|&&size| size == 3).count(), 3);
+        for (tie_break, expected) in [
+            (TieBreak::FirstFound, Square::new(0, 2, 3)),
+            (TieBreak::LowestXY, Square::new(0, 0, 3)),
+            (TieBreak::HighestXY, Square::new(1, 0, 3)),
+        ] {
+            let mut pumpkins = pumpkins.clone().with_tie_break(tie_break);
+            assert_eq!(pumpkins.add(1, 2), Ok(expected));
+            assert_eq!(pumpkins.validate(), Ok(()));
+        }
+
+        // the policy doesn't change which size wins
+        let mut order: Vec<_> = (0..36).map(|i| (i % 6, i / 6)).collect();
+        order.shuffle(&mut rand::thread_rng());
+        for tie_break in [TieBreak::LowestXY, TieBreak::HighestXY] {
+            let mut pumpkins = PumpkinPatch::new_make_table(6).with_tie_break(tie_break);
+            for &(x, y) in &order {
+                let expected = naive_merge_size(&pumpkins, x, y);
+                assert_eq!(pumpkins.add(x, y).unwrap().size(), expected);
+                assert_eq!(pumpkins.validate(), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_candidates() {
+        let mut pumpkins = PumpkinPatch::new_make_table(3);
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (2, 1), (1, 2), (2, 2)] {
+            pumpkins.add(x, y).unwrap();
+        }
+
+        // the two 2x2s around the center are complete, the other two miss a corner
+        let candidates = pumpkins.candidates(1, 1);
+        assert_eq!(
+            candidates,
+            [
+                Square::new(1, 1, 1),
+                Square::new(0, 0, 2),
+                Square::new(1, 1, 2)
+            ]
+        );
+        assert_eq!(
+            candidates.last().unwrap().size(),
+            pumpkins.preview(1, 1).unwrap().size()
+        );
+        assert!(pumpkins.candidates(0, 0).is_empty());
+        assert!(pumpkins.candidates(3, 0).is_empty());
+
+        // a filled square that would cut through a pumpkin isn't a candidate
+        let mut pumpkins = PumpkinPatch::new_make_table(3);
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1), (2, 0), (2, 1), (0, 2)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        assert_eq!(pumpkins.get_square_at(0, 0), Some(Square::new(0, 0, 2)));
+        assert_eq!(pumpkins.candidates(1, 2), [Square::new(1, 2, 1)]);
+    }
+
+    #[test]
+    fn test_patch_eq() {
+        let order = [(0, 0), (1, 0), (0, 1), (2, 2), (1, 1)];
+        let mut a = PumpkinPatch::new_make_table(3);
+        let mut b = PumpkinPatch::new_make_table(3);
+        assert!(!Rc::ptr_eq(&a.lookup_table, &b.lookup_table));
+        assert_eq!(a, b);
+
+        for (x, y) in order {
+            a.add(x, y).unwrap();
+        }
+        assert_ne!(a, b);
+        for (x, y) in order {
+            b.add(x, y).unwrap();
+        }
+        assert_eq!(a, b);
+
+        // the same cells merged differently aren't equal
+        let mut c = PumpkinPatch::new_make_table(3);
+        let mut d = PumpkinPatch::new_make_table(3);
+        for (x, y) in [(0, 0), (1, 0), (2, 0), (0, 1), (1, 1), (2, 1)] {
+            c.add(x, y).unwrap();
+        }
+        for (x, y) in [(2, 0), (1, 0), (2, 1), (1, 1), (0, 0), (0, 1)] {
+            d.add(x, y).unwrap();
+        }
+        assert_eq!(c.bitmap, d.bitmap);
+        assert_ne!(c, d);
+
+        assert_ne!(
+            PumpkinPatch::new_make_table(3),
+            PumpkinPatch::new_make_table(4)
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut pumpkins = PumpkinPatch::new_make_table(3);
+        for (x, y) in [(0, 0), (1, 0), (0, 1)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        let before = pumpkins.clone();
+        assert!(before.diff(&pumpkins).is_empty());
+
+        // the merge takes over the three other cells of the square, (0, 0) keeps its id
+        pumpkins.add(1, 1).unwrap();
+        let id = |id| NonZeroU32::new(id);
+        assert_eq!(
+            before.diff(&pumpkins),
+            [
+                CellChange {
+                    x: 1,
+                    y: 0,
+                    from: id(2),
+                    to: id(1)
+                },
+                CellChange {
+                    x: 0,
+                    y: 1,
+                    from: id(4),
+                    to: id(1)
+                },
+                CellChange {
+                    x: 1,
+                    y: 1,
+                    from: None,
+                    to: id(1)
+                },
+            ]
+        );
+        assert_eq!(pumpkins.diff(&before)[2].to, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "different dimensions")]
+    fn test_diff_mismatched() {
+        PumpkinPatch::new_make_table(3).diff(&PumpkinPatch::new_make_table(4));
+    }
+
+    #[test]
+    fn test_empty_cells() {
+        let table = Rc::new(LookupTable::new_rect(4, 3));
+        let mut pumpkins = PumpkinPatch::new_rect(4, 3, table);
+        assert_eq!(pumpkins.empty_cells().count(), 12);
+        assert_eq!(pumpkins.empty_cells().nth(5), Some((1, 1)));
+
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1), (3, 2)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        let empty: Vec<_> = pumpkins.empty_cells().collect();
+        assert_eq!(empty.len(), pumpkins.remaining_cells());
+        assert!(empty.iter().all(|&(x, y)| !pumpkins.contains(x, y)));
+        assert_eq!(empty[..3], [(2, 0), (3, 0), (2, 1)]);
+        assert_eq!(empty.last(), Some(&(2, 2)));
+    }
+
+    #[test]
+    fn test_pumpkin_neighbors() {
+        let id = |id| NonZeroU32::new(id).unwrap();
+        let ids = |ids: &[u32]| -> Vec<_> { ids.iter().map(|&i| id(i)).collect() };
+
+        // nine size-1 pumpkins, ids 1 to 9 row by row
+        let mut pumpkins = PumpkinPatch::new_make_table(3).with_max_square_size(1);
+        for (x, y) in (0..3).flat_map(|y| (0..3).map(move |x| (x, y))) {
+            pumpkins.add(x, y).unwrap();
+        }
+        assert_eq!(pumpkins.pumpkin_neighbors(id(5)), ids(&[2, 4, 6, 8]));
+        assert_eq!(pumpkins.pumpkin_neighbors(id(1)), ids(&[2, 4]));
+        assert_eq!(pumpkins.pumpkin_neighbors(id(6)), ids(&[3, 5, 9]));
+        assert!(pumpkins.pumpkin_neighbors(id(10)).is_empty());
+
+        // a larger pumpkin borders everything along its sides, empty cells are skipped
+        let mut pumpkins = PumpkinPatch::new_make_table(3);
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1), (2, 0), (0, 2)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        assert_eq!(pumpkins.pumpkin_neighbors(id(1)), ids(&[3, 7]));
+        assert!(pumpkins.pumpkin_neighbors(id(5)).is_empty());
+
+        // on a torus the cells past the edges wrap around
+        let table = Rc::new(LookupTable::with_topology(3, 3, Topology::Toroidal));
+        let mut pumpkins = PumpkinPatch::new(3, table).with_max_square_size(1);
+        for (x, y) in [(0, 0), (2, 0), (0, 2)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        assert_eq!(pumpkins.pumpkin_neighbors(id(1)), ids(&[3, 7]));
+    }
+
+    #[test]
+    fn test_add_with_stats() {
+        // the first insert only looks at its own cell and the four 2x2s around it
+        let mut pumpkins = PumpkinPatch::new_make_table(10);
+        let (square, stats) = pumpkins.add_with_stats(5, 5).unwrap();
+        assert_eq!(square, Square::new(5, 5, 1));
+        assert_eq!(
+            stats,
+            AddStats {
+                squares_visited: 5,
+                boundary_checks: 0,
+                max_square_tried: 1
+            }
+        );
+        let (_, stats) = pumpkins.add_with_stats(0, 0).unwrap();
+        assert_eq!(stats.squares_visited, 2);
+
+        // the stats don't change what gets merged
+        let mut order: Vec<_> = (0..6 * 6).map(|i| (i % 6, i / 6)).collect();
+        order.shuffle(&mut rand::thread_rng());
+        let mut counted = PumpkinPatch::new_make_table(6);
+        let mut plain = PumpkinPatch::new_make_table(6);
+        let mut last = AddStats::default();
+        for &(x, y) in &order {
+            let (square, stats) = counted.add_with_stats(x, y).unwrap();
+            assert_eq!(Ok(square), plain.add(x, y));
+            assert!(stats.max_square_tried >= square.size());
+            assert!(stats.squares_visited >= stats.boundary_checks);
+            last = stats;
+        }
+        assert_eq!(counted, plain);
+        assert_eq!(last.max_square_tried, 6);
+        assert!(last.boundary_checks >= 1);
+        assert_eq!(counted.add_with_stats(0, 0), Err(AddError::AlreadyFilled));
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut pumpkins = PumpkinPatch::builder()
+            .size(4)
+            .max_square_size(2)
+            .build()
+            .unwrap();
+        assert_eq!((pumpkins.width(), pumpkins.height()), (4, 4));
+        assert_eq!(pumpkins.max_square_size(), Some(2));
+        assert_eq!(pumpkins.topology(), Topology::Bounded);
+        for (x, y) in (0..4).flat_map(|y| (0..4).map(move |x| (x, y))) {
+            pumpkins.add(x, y).unwrap();
+        }
+        assert_eq!(pumpkins.pumpkin_count(), 4);
+
+        // the grid comes from a shared table, Arc tables work the same way
+        let table = std::sync::Arc::new(LookupTable::with_topology(5, 3, Topology::Toroidal));
+        let pumpkins = PumpkinPatchBuilder::new()
+            .lookup_table(table.clone())
+            .min_square_size(2)
+            .build()
+            .unwrap();
+        assert_eq!((pumpkins.width(), pumpkins.height()), (5, 3));
+        assert_eq!(pumpkins.topology(), Topology::Toroidal);
+        assert_eq!(pumpkins.min_square_size(), Some(2));
+
+        let pumpkins = PumpkinPatch::builder()
+            .rect(3, 2)
+            .topology(Topology::Toroidal)
+            .build()
+            .unwrap();
+        assert_eq!(pumpkins.lookup_table.topology(), Topology::Toroidal);
+
+        assert_eq!(
+            PumpkinPatch::builder().build().unwrap_err(),
+            BuildError::MissingSize
+        );
+        assert_eq!(
+            PumpkinPatch::builder().rect(0, 3).build().unwrap_err(),
+            BuildError::EmptyGrid
+        );
+        assert_eq!(
+            PumpkinPatch::builder()
+                .size(4)
+                .min_square_size(3)
+                .max_square_size(2)
+                .build()
+                .unwrap_err(),
+            BuildError::MinAboveMax { min: 3, max: 2 }
+        );
+        assert_eq!(
+            PumpkinPatch::builder()
+                .size(4)
+                .max_square_size(0)
+                .build()
+                .unwrap_err(),
+            BuildError::ZeroSquareSize
+        );
+        assert_eq!(
+            PumpkinPatchBuilder::new()
+                .size(4)
+                .lookup_table(table.clone())
+                .build()
+                .unwrap_err(),
+            BuildError::TableMismatch {
+                width: 5,
+                height: 3
+            }
+        );
+        assert_eq!(
+            PumpkinPatchBuilder::new()
+                .lookup_table(table)
+                .topology(Topology::Bounded)
+                .build()
+                .unwrap_err(),
+            BuildError::TopologyMismatch {
+                table: Topology::Toroidal
+            }
+        );
+    }
+
+    #[test]
+    fn test_empty_regions() {
+        let table = Rc::new(LookupTable::new_rect(4, 3));
+        let mut pumpkins = PumpkinPatch::new_rect(4, 3, table);
+        assert_eq!(
+            pumpkins.empty_regions(),
+            [pumpkins.empty_cells().collect::<Vec<_>>()]
+        );
+
+        // a filled middle row splits the grid in two
+        for x in 0..4 {
+            pumpkins.add(x, 1).unwrap();
+        }
+        assert_eq!(
+            pumpkins.empty_regions(),
+            [
+                vec![(0, 0), (1, 0), (2, 0), (3, 0)],
+                vec![(0, 2), (1, 2), (2, 2), (3, 2)]
+            ]
+        );
+
+        // cells only touching at a corner are separate regions
+        pumpkins.add(1, 0).unwrap();
+        pumpkins.add(0, 2).unwrap();
+        pumpkins.add(2, 2).unwrap();
+        assert_eq!(
+            pumpkins.empty_regions(),
+            [
+                vec![(0, 0)],
+                vec![(2, 0), (3, 0)],
+                vec![(1, 2)],
+                vec![(3, 2)]
+            ]
+        );
+
+        for (x, y) in pumpkins.empty_cells().collect::<Vec<_>>() {
+            pumpkins.add(x, y).unwrap();
+        }
+        assert!(pumpkins.empty_regions().is_empty());
+
+        // on a torus the top and bottom rows touch
+        let table = Rc::new(LookupTable::with_topology(4, 3, Topology::Toroidal));
+        let mut pumpkins = PumpkinPatch::new_rect(4, 3, table);
+        for x in 0..4 {
+            pumpkins.add(x, 1).unwrap();
+        }
+        assert_eq!(pumpkins.empty_regions().len(), 1);
+    }
+
+    #[test]
+    fn test_maximal_squares() {
+        let mut pumpkins = PumpkinPatch::new_make_table(3);
+        assert_eq!(pumpkins.maximal_squares(), []);
+
+        for (x, y) in Square::new(0, 0, 3).iter_cells() {
+            pumpkins.add(x, y).unwrap();
+        }
+        assert_eq!(pumpkins.maximal_squares(), [Square::new(0, 0, 3)]);
+
+        // two overlapping 2x2 squares, the corner cells are only covered by 1x1 ones
+        let mask = "
+            ###.
+            .##.
+            .###
+        ";
+        let pumpkins =
+            PumpkinPatch::from_mask_str(mask, Rc::new(LookupTable::new_rect(4, 3))).unwrap();
+        assert_eq!(
+            pumpkins.maximal_squares(),
+            [
+                Square::new(1, 0, 2),
+                Square::new(3, 0, 1),
+                Square::new(1, 1, 2),
+                Square::new(0, 2, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_largest_empty_square() {
+        let mut pumpkins = PumpkinPatch::new_make_table(4);
+        assert_eq!(pumpkins.largest_empty_square(), Some(Square::new(0, 0, 4)));
+
+        pumpkins.add(0, 0).unwrap();
+        assert_eq!(pumpkins.largest_empty_square(), Some(Square::new(1, 0, 3)));
+
+        pumpkins.add(3, 3).unwrap();
+        assert_eq!(pumpkins.largest_empty_square(), Some(Square::new(1, 0, 3)));
+        pumpkins.add(3, 0).unwrap();
+        assert_eq!(pumpkins.largest_empty_square(), Some(Square::new(0, 1, 3)));
+        pumpkins.add(0, 3).unwrap();
+        assert_eq!(pumpkins.largest_empty_square(), Some(Square::new(1, 0, 2)));
+
+        for (x, y) in (0..4).flat_map(|y| (0..4).map(move |x| (x, y))) {
+            pumpkins.add(x, y).ok();
+        }
+        assert_eq!(pumpkins.largest_empty_square(), None);
+
+        // rectangular grids are limited by their short side
+        let table = Rc::new(LookupTable::new_rect(5, 3));
+        let mut pumpkins = PumpkinPatch::new_rect(5, 3, table);
+        pumpkins.add(0, 1).unwrap();
+        assert_eq!(pumpkins.largest_empty_square(), Some(Square::new(1, 0, 3)));
+    }
+
+    #[test]
+    fn test_sequential_ids() {
+        let mut pumpkins = PumpkinPatch::builder()
+            .size(4)
+            .sequential_ids()
+            .build()
+            .unwrap();
+
+        for (x, y) in [(0, 0), (3, 3), (1, 0)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        let seq = |pumpkins: &PumpkinPatch, x, y| pumpkins.sequential_id(x, y).map(|id| id.get());
+        assert_eq!(seq(&pumpkins, 0, 0), Some(1));
+        assert_eq!(seq(&pumpkins, 3, 3), Some(2));
+        assert_eq!(seq(&pumpkins, 1, 0), Some(3));
+        assert_eq!(seq(&pumpkins, 2, 2), None);
+
+        // the merge replaces pumpkins 1 and 3 with pumpkin 5, the origin can still be recovered
+        pumpkins.add(0, 1).unwrap();
+        pumpkins.add(1, 1).unwrap();
+        assert_eq!(seq(&pumpkins, 0, 0), Some(5));
+        assert_eq!(seq(&pumpkins, 1, 1), Some(5));
+        let nz = |n| NonZeroU32::new(n).unwrap();
+        assert_eq!(
+            pumpkins.sequential_square(nz(5)),
+            Some(Square::new(0, 0, 2))
+        );
+        assert_eq!(
+            pumpkins.sequential_square(nz(2)),
+            Some(Square::new(3, 3, 1))
+        );
+        assert_eq!(pumpkins.sequential_square(nz(1)), None);
+        assert_eq!(pumpkins.sequential_square(nz(4)), None);
+        assert_eq!(pumpkins.sequential_square(nz(6)), None);
+
+        pumpkins.remove(3, 3);
+        assert_eq!(pumpkins.sequential_square(nz(2)), None);
+
+        // existing pumpkins are numbered by origin, patches without the option have none
+        let plain = PumpkinPatch::from_mask_str("#.\n.#", Rc::new(LookupTable::new(2))).unwrap();
+        assert_eq!(plain.sequential_id(0, 0), None);
+        let numbered = plain.with_sequential_ids();
+        assert_eq!(seq(&numbered, 1, 0), Some(1));
+        assert_eq!(seq(&numbered, 0, 1), Some(2));
+
+        pumpkins.clear();
+        pumpkins.add(2, 2).unwrap();
+        assert_eq!(seq(&pumpkins, 2, 2), Some(1));
+    }
+
+    #[test]
+    fn test_inserter() {
+        let size = 6;
+        let mut order: Vec<_> = (0..size * size).map(|i| (i % size, i / size)).collect();
+        order.shuffle(&mut rand::thread_rng());
+
+        let mut expected = PumpkinPatch::new_make_table(size);
+        let squares: Vec<_> = order
+            .iter()
+            .map(|&(x, y)| expected.add(x, y).unwrap())
+            .collect();
+
+        // repeated and out of bounds cells are skipped
+        let mut with_junk = order.clone();
+        with_junk.insert(3, order[0]);
+        with_junk.push((size, 0));
+        with_junk.push(order[5]);
+
+        let mut pumpkins = PumpkinPatch::new_make_table(size);
+        let mut inserter = pumpkins.inserter(with_junk);
+        assert_eq!(inserter.by_ref().take(2).collect::<Vec<_>>(), squares[..2]);
+        assert_eq!(inserter.collect::<Vec<_>>(), squares[2..]);
+        assert_eq!(pumpkins.ids, expected.ids);
+    }
+
+    #[test]
+    fn test_add_many() {
+        let size = 8;
+        let mut order: Vec<_> = (0..size * size).map(|i| (i % size, i / size)).collect();
+        order.shuffle(&mut rand::thread_rng());
+        order.push(order[0]);
+
+        let mut expected = PumpkinPatch::new_make_table(size);
+        let squares: Vec<_> = order
+            .iter()
+            .filter_map(|&(x, y)| expected.add(x, y).ok())
+            .collect();
+
+        let mut pumpkins = PumpkinPatch::new_make_table(size);
+        assert_eq!(pumpkins.add_many(&order), squares);
+        assert_eq!(pumpkins, expected);
+        assert!(pumpkins.validate().is_ok());
+    }
+
+    #[test]
+    fn test_random_fill() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let size = 10;
+        let mut pumpkins = PumpkinPatch::new_make_table(size);
+        let squares = pumpkins.random_fill(&mut StdRng::seed_from_u64(7));
+        assert!(pumpkins.is_full());
+        assert!(pumpkins.validate().is_ok());
+        assert_eq!(squares.len(), (size * size) as usize);
+        assert_eq!(squares.last(), Some(&Square::new(0, 0, size)));
+
+        // the same seed gives the same fill, only the empty cells are added
+        let mut again = PumpkinPatch::new_make_table(size);
+        assert_eq!(again.random_fill(&mut StdRng::seed_from_u64(7)), squares);
+        assert!(again.random_fill(&mut StdRng::seed_from_u64(7)).is_empty());
+
+        let mut partial = PumpkinPatch::new_make_table(size);
+        partial.add(3, 4).unwrap();
+        assert_eq!(partial.random_fill(&mut rand::thread_rng()).len(), 99);
+        assert!(partial.is_full());
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut pumpkins = PumpkinPatch::new_make_table(4).with_sequential_ids();
+        for (x, y) in [(0, 0), (1, 0), (3, 3)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        let before = pumpkins.clone();
+        let snapshot = pumpkins.snapshot();
+
+        for (x, y) in [(0, 1), (1, 1), (2, 2), (3, 2)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        pumpkins.remove(3, 3);
+        assert_ne!(pumpkins, before);
+
+        pumpkins.restore(&snapshot);
+        assert_eq!(pumpkins, before);
+        assert_eq!(pumpkins.filled_cells(), 3);
+        assert_eq!(pumpkins.sequential_id(3, 3), before.sequential_id(3, 3));
+        assert_eq!(pumpkins.undo(), None);
+        assert!(pumpkins.validate().is_ok());
+
+        // the snapshot can be restored again
+        pumpkins.add(1, 1).unwrap();
+        pumpkins.restore(&snapshot);
+        assert_eq!(pumpkins, before);
+    }
+
+    #[test]
+    #[should_panic(expected = "different dimensions")]
+    fn test_restore_mismatched() {
+        let snapshot = PumpkinPatch::new_make_table(3).snapshot();
+        PumpkinPatch::new_make_table(4).restore(&snapshot);
+    }
+
+    #[test]
+    fn test_undo() {
+        let mut pumpkins = PumpkinPatch::builder()
+            .size(4)
+            .sequential_ids()
+            .undo()
+            .build()
+            .unwrap();
+        for (x, y) in [(0, 0), (1, 0), (0, 1)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        let before = pumpkins.clone();
+
+        assert_eq!(pumpkins.add(1, 1), Ok(Square::new(0, 0, 2)));
+        assert_eq!(pumpkins.undo(), Some(Square::new(0, 0, 2)));
+        assert_eq!(pumpkins, before);
+        assert_eq!(pumpkins.pumpkin_count(), 3);
+        assert_eq!(pumpkins.filled_cells(), 3);
+        assert!(!pumpkins.contains(1, 1));
+        assert!(pumpkins.validate().is_ok());
+        assert_eq!(pumpkins.sequential_id(1, 0).map(|id| id.get()), Some(2));
+
+        // undoing everything in reverse order empties the patch
+        let mut order: Vec<_> = (0..16).map(|i| (i % 4, i / 4)).collect();
+        order.shuffle(&mut rand::thread_rng());
+        let mut pumpkins = PumpkinPatch::new_make_table(4).with_undo();
+        let mut states = vec![pumpkins.clone()];
+        let mut squares = vec![];
+        for (x, y) in order {
+            squares.push(pumpkins.add(x, y).unwrap());
+            states.push(pumpkins.clone());
+        }
+        states.pop();
+        while let Some(state) = states.pop() {
+            assert_eq!(pumpkins.undo(), squares.pop());
+            assert_eq!(pumpkins, state);
+        }
+        assert_eq!(pumpkins.undo(), None);
+
+        // deferred cells are simply emptied, `remove` forgets the history
+        let mut pumpkins = PumpkinPatch::new_make_table(4)
+            .with_min_square_size(2)
+            .with_undo();
+        assert_eq!(pumpkins.add(0, 0), Err(AddError::Deferred));
+        assert_eq!(pumpkins.undo(), Some(Square::new(0, 0, 1)));
+        assert_eq!(pumpkins.filled_cells(), 0);
+        pumpkins.add(3, 3).ok();
+        pumpkins.remove(3, 3);
+        assert_eq!(pumpkins.undo(), None);
+
+        // neither do the cells merged back by `remove`
+        let mut pumpkins = PumpkinPatch::new_make_table(3).with_undo();
+        for idx in 0..9 {
+            pumpkins.add(idx % 3, idx / 3).unwrap();
+        }
+        pumpkins.remove(2, 2);
+        assert_eq!(pumpkins.filled_cells(), 8);
+        assert_eq!(pumpkins.undo(), None);
+        assert_eq!(pumpkins.filled_cells(), 8);
+
+        // a parsed patch has nothing to undo
+        let table = Rc::new(LookupTable::new(2));
+        let pumpkins = PumpkinPatch::from_mask_str("##\n##", table.clone()).unwrap();
+        let mut pumpkins = pumpkins.with_undo();
+        assert_eq!(pumpkins.undo(), None);
+        assert_eq!(pumpkins.filled_cells(), 4);
+        let grid = vec![vec![true, false], vec![true, true]];
+        let pumpkins = PumpkinPatch::from_bool_grid(&grid, table).unwrap();
+        let mut pumpkins = pumpkins.with_undo();
+        assert_eq!(pumpkins.undo(), None);
+        assert_eq!(pumpkins.add(1, 1), Ok(Square::new(0, 0, 2)));
+        assert_eq!(pumpkins.undo(), Some(Square::new(0, 0, 2)));
+        assert_eq!(pumpkins.undo(), None);
+        assert_eq!(pumpkins.filled_cells(), 3);
+
+        // without `with_undo` nothing is recorded
+        let mut pumpkins = PumpkinPatch::new_make_table(2);
+        pumpkins.add(0, 0).unwrap();
+        assert!(pumpkins.history.is_none());
+        assert_eq!(pumpkins.undo(), None);
+        assert_eq!(pumpkins.filled_cells(), 1);
+    }
+
+    #[test]
+    fn test_counts() {
+        let mut pumpkins = PumpkinPatch::new_make_table(2);
+        assert_eq!(pumpkins.pumpkin_count(), 0);
+        assert_eq!(pumpkins.filled_cells(), 0);
+        assert_eq!(pumpkins.coverage(), 0.0);
+
+        let order = [(0, 0), (0, 1), (1, 0), (1, 1)];
+        let counts = [1, 2, 3, 1];
+        for (i, ((x, y), count)) in order.into_iter().zip(counts).enumerate() {
+            pumpkins.add(x, y).unwrap();
+            assert_eq!(pumpkins.pumpkin_count(), count);
+            assert_eq!(pumpkins.filled_cells(), i + 1);
+            assert_eq!(pumpkins.coverage(), (i + 1) as f64 / 4.0);
+        }
+    }
+
+    #[test]
+    fn test_merge_3() {
+        let mut pumpkins = PumpkinPatch::new_make_table(3);
+        // make this kind of pattern
+        // # # 0
+        // # # #
+        // 0 # #
+        let order: &[(u16, u16)] = &[
+            (2, 2),
+            (2, 1),
+            (1, 2),
+            (1, 1),
+            (1, 0),
+            (0, 1),
+            (0, 0),
+            (2, 0), // add the 0's to make a 3x3
+            (0, 2),
+        ];
+
+        let sqs = &[
+            (2, 2, 1),
+            (2, 1, 1),
+            (1, 2, 1),
+            (1, 1, 2),
+            (1, 0, 1),
+            (0, 1, 1),
+            (0, 0, 1),
+            (2, 0, 1),
+            (0, 0, 3),
+        ];
+
+        assert_eq!(pumpkins.pumpkins().count(), 0);
+
+        let transcript = PumpkinPatch::replay(3, order, Rc::new(LookupTable::new(3)));
+        let expected: Vec<_> = order
+            .iter()
+            .zip(sqs)
+            .map(|(&(x, y), &(sx, sy, size))| (x, y, Square::new(sx, sy, size)))
+            .collect();
+        assert_eq!(transcript, expected);
+
+        for ((x, y), (e_x, e_y, e_size)) in order.iter().zip(sqs.iter()) {
+            let sq = pumpkins.add(*x, *y).unwrap();
+            println!("{}", pumpkins);
+            assert_eq!(pumpkins.validate(), Ok(()));
+            assert!(
+                sq.size.get() == *e_size,
+                "Square should be size {}, got {}",
+                e_size,
+                sq.size
+            );
+            assert!(
+                (sq.x, sq.y) == (*e_x, *e_y),
+                "Got {:?}, expected {:?}",
+                (sq.x, sq.y),
+                (e_x, e_y)
+            );
+        }
+
+        let id = pumpkins.get(0, 0).unwrap();
+        assert_eq!(
+            pumpkins.pumpkins().collect::<Vec<_>>(),
+            [(id, Square::new(0, 0, 3))]
+        );
+        for (x, y) in Square::new(0, 0, 3).iter_cells() {
+            assert_eq!(pumpkins.get_square_at(x, y), Some(Square::new(0, 0, 3)));
+        }
+        assert_eq!(pumpkins.to_csv(), "id,x,y,size,cells\n1,0,0,3,9\n");
+        assert_eq!(pumpkins.to_grid(), vec![vec![1; 3]; 3]);
+        assert_eq!(pumpkins.size_map(), [3; 9]);
+        assert!(pumpkins.same_pumpkin((0, 0), (2, 2)));
+        assert!(pumpkins.same_pumpkin((2, 0), (0, 2)));
+        assert!(!pumpkins.same_pumpkin((0, 0), (3, 0)));
+        assert_eq!(pumpkins.size_histogram(), BTreeMap::from([(3, 1)]));
+    }
+
+    #[test]
+    fn test_to_csv() {
+        assert_eq!(
+            PumpkinPatch::new_make_table(3).to_csv(),
+            "id,x,y,size,cells\n"
+        );
+
+        let mask = "
+            #.#
+            ##.
+            ##.
+        ";
+        let pumpkins = PumpkinPatch::from_mask_str(mask, Rc::new(LookupTable::new(3))).unwrap();
+        assert_eq!(
+            pumpkins.to_csv(),
+            "id,x,y,size,cells\n1,0,0,2,4\n7,0,2,1,1\n9,2,2,1,1\n"
+        );
+    }
+
+    #[cfg(not(feature = "trace-merge"))]
+    #[test]
+    fn test_no_trace_output() {
+        // Re-run test_merge_3 in a child process with output capturing turned off
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["graph::tests::test_merge_3", "--exact", "--nocapture"])
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+
+        assert!(output.status.success());
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+        for noise in ["Checking boundary", "NORTH", "SOUTH", "EAST", "WEST"] {
+            assert!(!stdout.contains(noise), "found {:?} in {}", noise, stdout);
+        }
+    }
+
+    #[test]
+    fn test_from_bitmap() {
+        for size in 1..=8 {
+            let full = BitVec::from_elem(size as usize * size as usize, true);
+            let pumpkins = PumpkinPatch::from_bitmap(size, &full, Rc::new(LookupTable::new(size)));
+            assert!(pumpkins.is_full());
+            assert_eq!(
+                pumpkins.pumpkins().map(|(_, sq)| sq).collect::<Vec<_>>(),
+                vec![Square::new(0, 0, size)]
+            );
+        }
+
+        // A partial mask matches adding its cells in row-major order
+        let size = 6;
+        let table = Rc::new(LookupTable::new(size));
+        let mut mask = BitVec::from_elem(36, false);
+        let mut expected = PumpkinPatch::new(size, table.clone());
+        for idx in 0..36 {
+            if rand::random::<f64>() < 0.7 {
+                mask.set(idx, true);
+                expected.add(idx as u16 % size, idx as u16 / size).unwrap();
+            }
+        }
+
+        let pumpkins = PumpkinPatch::from_bitmap(size, &mask, table);
+        assert_eq!(pumpkins.bitmap, Bitmap::from(&mask));
+        assert_eq!(pumpkins.filled_cells(), expected.filled_cells());
+        assert_eq!(pumpkins.ids, expected.ids);
+    }
+
+    #[test]
+    #[should_panic(expected = "mask doesn't match the grid size")]
+    fn test_from_bitmap_wrong_size() {
+        PumpkinPatch::from_bitmap(3, &BitVec::from_elem(8, true), Rc::new(LookupTable::new(3)));
+    }
+
+    // Cell by cell version of `check_boundary` on a bounded grid: a square is rejected when a
+    // cell on its perimeter shares its id with a neighbor just outside the square
+    fn naive_check_boundary(patch: &PumpkinPatch, sq: &Square) -> bool {
+        let outside = |x: i32, y: i32| {
+            let (x0, y0, size) = (sq.x() as i32, sq.y() as i32, sq.size() as i32);
+            let in_grid = x >= 0 && y >= 0 && x < patch.width as i32 && y < patch.height as i32;
+            let in_square = x >= x0 && y >= y0 && x < x0 + size && y < y0 + size;
+            (in_grid && !in_square).then(|| patch.get(x as u16, y as u16))
+        };
+
+        sq.perimeter_cells().into_iter().all(|(x, y)| {
+            let id = patch.get(x, y);
+            [(1, 0), (-1, 0), (0, 1), (0, -1)].iter().all(|(dx, dy)| {
+                match outside(x as i32 + dx, y as i32 + dy) {
+                    Some(Some(other)) => Some(other) != id,
+                    _ => true,
+                }
+            })
+        })
+    }
+
+    // Brute force version of the merge search on a bounded grid: the size of the largest square
+    // containing the empty cell (x, y) whose other cells are all filled and that no pumpkin
+    // crosses
+    fn naive_merge_size(patch: &PumpkinPatch, x: u16, y: u16) -> u16 {
+        let (width, height) = (patch.width(), patch.height());
+        let mut best = 1;
+        for size in 2..=width.min(height) {
+            let origins = (y.saturating_sub(size - 1)..=y)
+                .flat_map(|oy| (x.saturating_sub(size - 1)..=x).map(move |ox| (ox, oy)));
+            for (ox, oy) in origins {
+                let sq = Square::new(ox, oy, size);
+                let filled = sq
+                    .iter_cells()
+                    .all(|(cx, cy)| (cx, cy) == (x, y) || patch.contains(cx, cy));
+                if sq.fits(width, height) && filled && naive_check_boundary(patch, &sq) {
+                    best = size;
+                }
+            }
+        }
+        best
+    }
+
+    #[test]
+    fn test_check_boundary() {
+        // rectangular grids step through the east and west columns with a different stride
+        // than their height
+        let grids = (3..=7)
+            .map(|size| (size, size))
+            .chain([(5, 3), (3, 6), (8, 4)]);
+        for (width, height) in grids {
+            let table = Rc::new(LookupTable::new_rect(width, height));
+            let mut pumpkins = PumpkinPatch::new_rect(width, height, table);
+            let mut order = (0..width * height).collect::<Vec<_>>();
+            order.shuffle(&mut rand::thread_rng());
+
+            for idx in order {
+                pumpkins.add(idx % width, idx / width).unwrap();
+
+                for idx in 0..pumpkins.lookup_table.num_squares() {
+                    let sq = Square::from_index(idx, width as usize, height as usize);
+                    if sq.fits(width, height) {
+                        assert_eq!(
+                            pumpkins.check_boundary(&sq),
+                            naive_check_boundary(&pumpkins, &sq),
+                            "{:?}",
+                            sq
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_patch_validate() {
+        let mut pumpkins = PumpkinPatch::new_make_table(3);
+        assert_eq!(pumpkins.validate(), Ok(()));
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1), (2, 2)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        assert_eq!(pumpkins.validate(), Ok(()));
+
+        let mut bad = pumpkins.clone();
+        bad.bitmap.set(2, true);
+        assert!(bad.validate().unwrap_err().starts_with("cell (2, 0)"));
+
+        // a pumpkin that isn't a square
+        let mut bad = pumpkins.clone();
+        bad.fill_ids(Square::new(1, 1, 1), NonZeroU32::new(9));
+        assert_eq!(
+            bad.validate(),
+            Err("cell (1, 1) is inside pumpkin 1 but has id Some(9)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_get_square_at() {
+        let mut pumpkins = PumpkinPatch::new_make_table(3);
+        assert_eq!(pumpkins.get_square_at(1, 1), None);
+
+        pumpkins.add(2, 1).unwrap();
+        assert_eq!(pumpkins.get_square_at(2, 1), Some(Square::new(2, 1, 1)));
+        assert_eq!(pumpkins.get_square_at(1, 1), None);
+        assert_eq!(pumpkins.get_square_at(3, 1), None);
+    }
+
+    #[test]
+    fn test_row_column() {
+        let table = Rc::new(LookupTable::new_rect(3, 2));
+        let mut pumpkins = PumpkinPatch::new_rect(3, 2, table);
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1), (2, 1)] {
+            pumpkins.add(x, y).unwrap();
+        }
+
+        let id = |x, y| Some(pumpkin_id(x, y, 3));
+        assert_eq!(pumpkins.row(0), [id(0, 0), id(0, 0), None]);
+        assert_eq!(pumpkins.row(1), [id(0, 0), id(0, 0), id(2, 1)]);
+        for x in 0..3 {
+            let column: Vec<_> = pumpkins.column(x).collect();
+            assert_eq!(column, [pumpkins.get(x, 0), pumpkins.get(x, 1)]);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn test_row_out_of_range() {
+        PumpkinPatch::new_make_table(3).row(3);
+    }
+
+    #[test]
+    fn test_ids_are_packed() {
+        use core::mem::size_of;
+        assert_eq!(size_of::<Option<NonZeroU32>>(), size_of::<u32>());
+    }
+
+    #[test]
+    fn test_out_of_bounds_cells() {
+        let table = Rc::new(LookupTable::new_rect(4, 3));
+        let mut pumpkins = PumpkinPatch::new_rect(4, 3, table);
+        for (x, y) in (0..3).flat_map(|y| (0..4).map(move |x| (x, y))) {
+            pumpkins.add(x, y).unwrap();
+        }
+
+        // (4, 0) would otherwise alias (0, 1)
+        for (x, y) in [(4, 0), (4, 3), (0, 3), (3, 4), (u16::MAX, u16::MAX)] {
+            assert_eq!(pumpkins.get(x, y), None);
+            assert!(!pumpkins.contains(x, y));
+            assert_eq!(pumpkins.get_square_at(x, y), None);
+            assert_eq!(pumpkins.sequential_id(x, y), None);
+        }
+    }
+
+    #[test]
+    fn test_pumpkins() {
+        // one step before the final merge of test_merge_3 there are 5 pumpkins
+        let mut pumpkins = PumpkinPatch::new_make_table(3);
+        for (x, y) in [
+            (2, 2),
+            (2, 1),
+            (1, 2),
+            (1, 1),
+            (1, 0),
+            (0, 1),
+            (0, 0),
+            (2, 0),
+        ] {
+            pumpkins.add(x, y).unwrap();
+        }
+
+        let squares: Vec<_> = pumpkins.pumpkins().map(|(_, sq)| sq).collect();
+        assert_eq!(
+            squares,
+            [
+                Square::new(0, 0, 1),
+                Square::new(1, 0, 1),
+                Square::new(2, 0, 1),
+                Square::new(0, 1, 1),
+                Square::new(1, 1, 2),
+            ]
+        );
+        for (id, sq) in pumpkins.pumpkins() {
+            assert!(sq.iter_cells().all(|(x, y)| pumpkins.get(x, y) == Some(id)));
+        }
+        assert_eq!(
+            pumpkins.to_grid(),
+            [[0, 5, 5], [4, 5, 5], [1, 2, 3]].map(Vec::from)
+        );
+        assert_eq!(pumpkins.size_histogram(), BTreeMap::from([(1, 4), (2, 1)]));
+
+        assert_eq!(pumpkins.size_map(), [1, 1, 1, 1, 2, 2, 0, 2, 2]);
+        assert!(!pumpkins.same_pumpkin((0, 0), (1, 0)));
+        assert!(!pumpkins.same_pumpkin((0, 1), (1, 1)));
+        assert!(pumpkins.same_pumpkin((1, 1), (2, 2)));
+        // empty cells don't form a pumpkin either
+        assert!(!pumpkins.same_pumpkin((0, 2), (0, 2)));
+
+        let ids = |ids: &[u32]| -> BTreeSet<_> {
+            ids.iter().map(|&id| NonZeroU32::new(id).unwrap()).collect()
+        };
+        assert_eq!(pumpkins.ids_in_region(0, 0, 2, 2), ids(&[1, 2, 4, 5]));
+        assert_eq!(pumpkins.ids_in_region(1, 1, 2, 2), ids(&[5]));
+        // (0, 2) is empty, the rest of the box is clipped
+        assert_eq!(pumpkins.ids_in_region(0, 2, 1, 5), ids(&[]));
+        assert_eq!(pumpkins.ids_in_region(2, 0, 10, 1), ids(&[3]));
+        assert_eq!(pumpkins.ids_in_region(3, 3, 2, 2), ids(&[]));
+        assert_eq!(pumpkins.ids_in_region(0, 0, 0, 3), ids(&[]));
+
+        let mut pumpkins = PumpkinPatch::new_make_table(3).with_max_square_size(1);
+        assert!(pumpkins.size_histogram().is_empty());
+        pumpkins.add_many(&Square::new(0, 0, 3).iter_cells().collect::<Vec<_>>());
+        assert_eq!(pumpkins.size_histogram(), BTreeMap::from([(1, 9)]));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut pumpkins = PumpkinPatch::new_make_table(2);
+        for (x, y) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        assert_eq!(
+            pumpkins.pumpkin_square(pumpkins.get(0, 0).unwrap()).size(),
+            2
+        );
+
+        pumpkins.remove(1, 1);
+        assert_eq!(pumpkins.validate(), Ok(()));
+        assert_eq!(pumpkins.filled_cells(), 3);
+        assert!(!pumpkins.contains(1, 1));
+        assert_eq!(pumpkins.get(1, 1), None);
+        for (x, y) in [(0, 0), (1, 0), (0, 1)] {
+            let id = pumpkins.get(x, y).unwrap();
+            assert_eq!(pumpkins.pumpkin_square(id), Square::new(x, y, 1));
+        }
+
+        // removing an empty cell is a no-op
+        let before = pumpkins.ids.clone();
+        pumpkins.remove(1, 1);
+        assert_eq!(pumpkins.ids, before);
+
+        // removing a corner of a size-3 pumpkin re-forms a size-2 pumpkin in the opposite corner
+        let mut pumpkins = PumpkinPatch::new_make_table(3);
+        for idx in 0..9 {
+            pumpkins.add(idx % 3, idx / 3).unwrap();
+        }
+        assert_eq!(
+            pumpkins.pumpkin_square(pumpkins.get(1, 1).unwrap()).size(),
+            3
+        );
+        pumpkins.remove(2, 2);
+        assert_eq!(pumpkins.validate(), Ok(()));
+        assert_eq!(
+            pumpkins.pumpkin_square(pumpkins.get(1, 1).unwrap()),
+            Square::new(0, 0, 2)
+        );
+        for (x, y) in [(2, 0), (2, 1), (0, 2), (1, 2)] {
+            let id = pumpkins.get(x, y).unwrap();
+            assert_eq!(pumpkins.pumpkin_square(id), Square::new(x, y, 1));
+        }
+    }
+
+    #[test]
+    fn test_clear() {
+        let size = 6;
+        let table = Rc::new(LookupTable::new(size));
+        let mut order = (0..size * size).collect::<Vec<_>>();
+        order.shuffle(&mut rand::thread_rng());
+
+        let mut reused = PumpkinPatch::new(size, table.clone());
+        for idx in order.iter().rev() {
+            reused.add(idx % size, idx / size).unwrap();
+        }
+        reused.clear();
+        assert!(reused.ids.iter().all(Option::is_none));
+        assert!(reused.bitmap.none());
+        assert_eq!(reused.remaining_cells(), (size * size) as usize);
+
+        let mut fresh = PumpkinPatch::new(size, table);
+        for idx in order {
+            let (x, y) = (idx % size, idx / size);
+            assert_eq!(reused.add(x, y).unwrap(), fresh.add(x, y).unwrap());
+        }
+        assert_eq!(reused.ids, fresh.ids);
+    }
+
+    #[test]
+    fn test_rect_fill() {
+        for (width, height) in [(2, 3), (3, 2)] {
+            let table = Rc::new(LookupTable::new_rect(width, height));
+            assert_eq!(table.size(), 2);
+            assert_eq!(table.validate(), Ok(()));
+
+            for _ in 0..20 {
+                let mut pumpkins = PumpkinPatch::new_rect(width, height, table.clone());
+                let squares = pumpkins.random_fill(&mut rand::thread_rng());
+                assert_eq!(squares.len(), (width * height) as usize);
+                assert!(pumpkins.is_full());
+                assert_eq!(pumpkins.add(width, 0), Err(AddError::OutOfBounds));
+                assert_eq!(pumpkins.add(0, height), Err(AddError::OutOfBounds));
+
+                let largest = pumpkins.pumpkins().map(|(_, sq)| sq.size()).max();
+                assert_eq!(largest, Some(2));
+                for (id, sq) in pumpkins.pumpkins() {
+                    assert!(sq.fits(width, height));
+                    assert!(sq.iter_cells().all(|(x, y)| pumpkins.get(x, y) == Some(id)));
+                }
+            }
         }
 
-        // the last one should merge with the first 3
-        let sq = pumpkins.add(1, 1);
-        println!("{}", pumpkins);
-        assert!(sq.size.get() == 2, "Square should be size 2");
-        assert!(
-            (sq.x, sq.y) == (0, 0),
-            "Square should be at the correct position"
-        );
+        // filling row by row merges the bottom two rows
+        let mut pumpkins = PumpkinPatch::new_rect(2, 3, Rc::new(LookupTable::new_rect(2, 3)));
+        let squares: Vec<_> = pumpkins
+            .inserter(vec![(0, 0), (1, 0), (0, 1), (1, 1), (0, 2), (1, 2)])
+            .collect();
+        assert_eq!(squares[3], Square::new(0, 0, 2));
+        assert_eq!(squares[5], Square::new(1, 2, 1));
     }
 
     #[test]
-    fn test_merge_3() {
-        let mut pumpkins = PumpkinPatch::new_make_table(3);
-        // make this kind of pattern
-        // # # 0
-        // # # #
-        // 0 # #
-        let order: &[(u16, u16)] = &[
-            (2, 2),
-            (2, 1),
-            (1, 2),
-            (1, 1),
-            (1, 0),
-            (0, 1),
-            (0, 0),
-            (2, 0), // add the 0's to make a 3x3
-            (0, 2),
-        ];
+    fn test_toroidal() {
+        let table = Rc::new(LookupTable::with_topology(3, 3, Topology::Toroidal));
+        assert_eq!(table.topology(), Topology::Toroidal);
+        assert_eq!(table.validate(), Ok(()));
+        assert_eq!(table.get_larger(Square::new(0, 0, 1)).len(), 4);
 
-        let sqs = &[
-            (2, 2, 1),
-            (2, 1, 1),
-            (1, 2, 1),
-            (1, 1, 2),
-            (1, 0, 1),
-            (0, 1, 1),
-            (0, 0, 1),
-            (2, 0, 1),
-            (0, 0, 3),
-        ];
+        // The four corners of a 3x3 torus are neighbors across both edges
+        let corners = vec![(2, 2), (0, 2), (2, 0), (0, 0)];
 
-        for ((x, y), (e_x, e_y, e_size)) in order.iter().zip(sqs.iter()) {
-            let sq = pumpkins.add(*x, *y);
-            println!("{}", pumpkins);
-            assert!(
-                sq.size.get() == *e_size,
-                "Square should be size {}, got {}",
-                e_size,
-                sq.size
-            );
-            assert!(
-                (sq.x, sq.y) == (*e_x, *e_y),
-                "Got {:?}, expected {:?}",
-                (sq.x, sq.y),
-                (e_x, e_y)
-            );
+        let mut pumpkins = PumpkinPatch::new(3, table.clone());
+        assert_eq!(pumpkins.topology(), Topology::Toroidal);
+        let squares: Vec<_> = pumpkins.inserter(corners.clone()).collect();
+        assert_eq!(squares[3], Square::new(2, 2, 2));
+        assert_eq!(pumpkins.pumpkin_count(), 1);
+        for &(x, y) in &corners {
+            assert_eq!(pumpkins.get_square_at(x, y), Some(Square::new(2, 2, 2)));
+        }
+        assert_eq!(pumpkins.get_square_at(1, 1), None);
+
+        assert_eq!(pumpkins.validate(), Ok(()));
+
+        // Removing one corner leaves the other three on their own
+        pumpkins.remove(0, 0);
+        assert_eq!(pumpkins.validate(), Ok(()));
+        assert_eq!(pumpkins.pumpkin_count(), 3);
+        assert_eq!(pumpkins.filled_cells(), 3);
+        assert_eq!(pumpkins.get_square_at(2, 2), Some(Square::new(2, 2, 1)));
+
+        // The same cells can't merge on a bounded grid
+        let mut bounded = PumpkinPatch::new_make_table(3);
+        assert_eq!(bounded.topology(), Topology::Bounded);
+        let squares: Vec<_> = bounded.inserter(corners).collect();
+        assert!(squares.iter().all(|sq| sq.size() == 1));
+
+        // A size 2 pumpkin across the wrap can't grow into one that splits its neighbor
+        let mut pumpkins = PumpkinPatch::new(3, table.clone());
+        for (x, y) in [(2, 0), (0, 0), (2, 1), (0, 1)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        assert_eq!(pumpkins.get_square_at(0, 0), Some(Square::new(2, 0, 2)));
+        assert_eq!(pumpkins.add(1, 0), Ok(Square::new(1, 0, 1)));
+
+        for _ in 0..20 {
+            let mut pumpkins = PumpkinPatch::new(3, table.clone());
+            assert_eq!(pumpkins.random_fill(&mut rand::thread_rng()).len(), 9);
+            assert_eq!(pumpkins.pumpkin_count(), 1);
+            assert_eq!(pumpkins.pumpkins().next().unwrap().1.size(), 3);
+        }
+    }
+
+    #[test]
+    fn test_large_ids() {
+        // every cell of a 300x300 grid gets its own id, past the range of a u16
+        let mut seen = std::collections::HashSet::new();
+        for y in 0..300 {
+            for x in 0..300 {
+                assert!(seen.insert(pumpkin_id(x, y, 300)));
+            }
+        }
+        assert_eq!(seen.len(), 300 * 300);
+        assert_eq!(pumpkin_id(299, 299, 300).get(), 90_000);
+
+        // A full table for a 300x300 grid is too big for a test, a wide grid reaches the same ids
+        let (width, height) = (40_000, 2);
+        let table = Rc::new(LookupTable::new_serial(
+            width,
+            height,
+            Topology::Bounded,
+            height,
+            false,
+            |_, _| {},
+        ));
+        let mut pumpkins = PumpkinPatch::new_rect(width, height, table);
+
+        pumpkins.add(width - 1, 1).unwrap();
+        pumpkins.add(width - 2, 1).unwrap();
+        pumpkins.add(0, 1).unwrap();
+
+        let ids: Vec<_> = pumpkins.pumpkins().map(|(id, _)| id.get()).collect();
+        assert_eq!(ids, vec![40_001, 79_999, 80_000]);
+        assert_eq!(
+            pumpkins.get_square_at(width - 1, 1),
+            Some(Square::new(width - 1, 1, 1))
+        );
+
+        pumpkins.add(width - 1, 0).unwrap();
+        assert_eq!(pumpkins.add(width - 2, 0), Ok(Square::new(width - 2, 0, 2)));
+        assert_eq!(pumpkins.pumpkin_count(), 2);
+    }
+
+    proptest::proptest! {
+        // Any insert order has to end with the whole grid merged into one pumpkin
+        #[test]
+        fn prop_fill_is_one_square(
+            (size, order) in (1u16..=10).prop_flat_map(|size| {
+                let cells: Vec<_> = (0..size).flat_map(|y| (0..size).map(move |x| (x, y))).collect();
+                (Just(size), Just(cells).prop_shuffle())
+            })
+        ) {
+            let mut pumpkins = PumpkinPatch::new_make_table(size);
+            let mut last = None;
+            for (x, y) in order {
+                last = Some(pumpkins.add(x, y).unwrap());
+            }
+            prop_assert_eq!(last, Some(Square::new(0, 0, size)));
+        }
+
+        // Every insert merges into a square as large as the brute force search finds
+        #[test]
+        fn prop_add_matches_brute_force(
+            (width, height, order) in (1u16..=6, 1u16..=6).prop_flat_map(|(width, height)| {
+                let cells: Vec<_> = (0..height)
+                    .flat_map(|y| (0..width).map(move |x| (x, y)))
+                    .collect();
+                (Just(width), Just(height), Just(cells).prop_shuffle())
+            })
+        ) {
+            let table = Rc::new(LookupTable::new_rect(width, height));
+            let mut pumpkins = PumpkinPatch::new_rect(width, height, table);
+            for (x, y) in order {
+                let expected = naive_merge_size(&pumpkins, x, y);
+                let before = pumpkins.clone();
+                let sq = pumpkins.add(x, y).unwrap();
+
+                prop_assert_eq!(sq.size(), expected);
+                prop_assert!(sq.contains(x, y) && sq.fits(width, height));
+                prop_assert!(naive_check_boundary(&before, &sq));
+                prop_assert_eq!(pumpkins.validate(), Ok(()));
+            }
         }
     }
 
@@ -488,16 +5224,600 @@ mod tests {
             for idx in order.iter().take(order.len() - 1) {
                 let x = idx % size;
                 let y = idx / size;
-                pumpkins.add(x, y);
+                pumpkins.add(x, y).unwrap();
+                assert_eq!(pumpkins.validate(), Ok(()));
             }
+            assert!(!pumpkins.is_full());
+            assert_eq!(pumpkins.remaining_cells(), 1);
 
             let x = order.last().unwrap() % size;
             let y = order.last().unwrap() / size;
             assert!(
-                pumpkins.add(x, y).size.get() == size,
+                pumpkins.add(x, y).unwrap().size.get() == size,
                 "Square should be size {}",
                 size
             );
+            assert!(pumpkins.is_full());
+            assert_eq!(pumpkins.remaining_cells(), 0);
+        }
+    }
+
+    #[test]
+    fn test_lookup_stats() {
+        for size in 1..=8u16 {
+            let table = LookupTable::new(size);
+            assert_eq!(table.size(), size);
+
+            let stats = table.stats();
+            assert_eq!(stats.num_squares, (size as usize).pow(3));
+            assert_eq!(stats.larger_squares, table.larger_squares.len());
+            assert!(stats.table_bytes > 0);
+            assert!(stats.bitmap_bytes > 0);
+        }
+
+        // a 1x1 grid can't grow
+        assert_eq!(LookupTable::new(1).stats().average_fanout, 0.0);
+        assert_eq!(
+            LookupTable::new(1).fanout_histogram(),
+            BTreeMap::from([(0, 1)])
+        );
+    }
+
+    #[test]
+    fn test_fanout_histogram() {
+        let table = LookupTable::new(4);
+        let histogram = table.fanout_histogram();
+        assert_eq!(histogram.values().sum::<usize>(), 4 * 4 * 4);
+        assert_eq!(
+            histogram
+                .iter()
+                .map(|(fanout, n)| fanout * n)
+                .sum::<usize>(),
+            table.larger_squares.len()
+        );
+        // no square grows in more than 4 directions
+        assert_eq!(histogram.keys().max(), Some(&4));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_parallel_lookup_table() {
+        for size in [1, 2, 5, 16] {
+            for topology in [Topology::Bounded, Topology::Toroidal] {
+                assert_eq!(
+                    LookupTable::new_serial(size, size, topology, size, true, |_, _| {}),
+                    LookupTable::new_parallel(size, size, topology, size)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_capped_lookup_table() {
+        let full = LookupTable::new(6);
+        let capped = LookupTable::new_capped(6, 3);
+        assert_eq!(capped.size(), 3);
+        assert_eq!(capped.validate(), Ok(()));
+        assert_eq!(capped.stats().num_squares, 6 * 6 * 3);
+        assert_eq!(LookupTable::new_capped(6, 10), full);
+
+        for idx in 0..capped.num_squares() {
+            let sq = Square::from_index(idx, 6, 6);
+            if !sq.fits(6, 6) {
+                continue;
+            }
+            assert_eq!(capped.get_smaller(sq), full.get_smaller(sq));
+            assert_eq!(capped.get_bitmap(sq), full.get_bitmap(sq));
+            if sq.size() < 3 {
+                assert!(capped.get_larger(sq).eq(full.get_larger(sq)));
+            } else {
+                assert_eq!(capped.get_larger(sq).len(), 0);
+            }
+        }
+
+        // the patch stops merging at the cap
+        let mut capped_patch = PumpkinPatch::new(6, Rc::new(capped));
+        let mut full_patch = PumpkinPatch::new(6, Rc::new(full)).with_max_square_size(3);
+        let mut order: Vec<_> = (0..36).map(|i| (i % 6, i / 6)).collect();
+        order.shuffle(&mut rand::thread_rng());
+        assert_eq!(capped_patch.add_many(&order), full_patch.add_many(&order));
+        assert_eq!(capped_patch, full_patch);
+    }
+
+    #[test]
+    fn test_lookup_table_progress() {
+        let mut calls = Vec::new();
+        let table = LookupTable::new_with_progress(20, |done, total| calls.push((done, total)));
+        assert_eq!(table, LookupTable::new(20));
+
+        let total = 20 * 20 * 20;
+        assert_eq!(calls.last(), Some(&(total, total)));
+        assert!(calls.len() <= 101);
+        assert!(calls.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(calls.iter().all(|&(_, t)| t == total));
+    }
+
+    #[test]
+    fn test_lean_lookup_table() {
+        for size in 1..=8 {
+            let full = Rc::new(LookupTable::new(size));
+            let lean = Rc::new(LookupTable::new_lean(size));
+            assert!(!full.is_lean());
+            assert!(lean.is_lean());
+            assert_eq!(lean.stats().bitmap_bytes, 0);
+
+            let mut order = (0..size * size).collect::<Vec<_>>();
+            order.shuffle(&mut rand::thread_rng());
+
+            let mut full_patch = PumpkinPatch::new(size, full);
+            let mut lean_patch = PumpkinPatch::new(size, lean);
+            let mut last = None;
+            for idx in order {
+                let (x, y) = (idx % size, idx / size);
+                let sq = full_patch.add(x, y).unwrap();
+                assert_eq!(sq, lean_patch.add(x, y).unwrap());
+                last = Some(sq);
+            }
+
+            assert_eq!(full_patch.ids, lean_patch.ids);
+            assert_eq!(last.unwrap().size(), size);
+        }
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_lookup_table_save_load() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("pumpkins-table-{}.bin", std::process::id()));
+
+        let table = LookupTable::new(12);
+        table.save(&path).unwrap();
+        let loaded = LookupTable::load(&path).unwrap();
+
+        for idx in 0..12 * 12 * 12 {
+            let sq = Square::from_index(idx, 12, 12);
+            if !sq.fits(12, 12) {
+                continue;
+            }
+            assert!(table.get_larger(sq).eq(loaded.get_larger(sq)));
+            assert_eq!(table.get_smaller(sq), loaded.get_smaller(sq));
+        }
+        assert_eq!(table, loaded);
+
+        // a truncated file is rejected instead of producing a broken table
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(LookupTable::load(&path).is_err());
+
+        // so is a table whose size doesn't match its contents
+        let mut bad = LookupTable::new(3);
+        bad.width = 4;
+        bad.save(&path).unwrap();
+        let err = LookupTable::load(&path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn test_render_png() {
+        let path = std::env::temp_dir().join(format!("pumpkins-{}.png", std::process::id()));
+
+        let mut pumpkins = PumpkinPatch::new_make_table(4);
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1), (3, 3)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        pumpkins.render_png(&path, 10).unwrap();
+
+        let image = image::open(&path).unwrap().into_rgb8();
+        assert_eq!(image.dimensions(), (40, 40));
+
+        // the size 2 pumpkin in the bottom-left is one color inside a black outline
+        let id = pumpkins.get(0, 0).unwrap();
+        assert_eq!(image.get_pixel(0, 39).0, [0, 0, 0]);
+        assert_eq!(image.get_pixel(5, 35).0, pumpkin_color(id));
+        assert_eq!(image.get_pixel(15, 25).0, pumpkin_color(id));
+        assert_ne!(image.get_pixel(35, 5).0, pumpkin_color(id));
+        // empty cells are background
+        assert_eq!(image.get_pixel(25, 35).0, [40, 40, 40]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn test_record_gif() {
+        use image::AnimationDecoder;
+
+        let path = std::env::temp_dir().join(format!("pumpkins-{}.gif", std::process::id()));
+
+        let order = [(0, 0), (1, 0), (0, 1), (1, 1), (1, 1), (2, 2)];
+        PumpkinPatch::record_gif(3, &order, &path, 4, 50).unwrap();
+
+        let file = std::io::BufReader::new(std::fs::File::open(&path).unwrap());
+        let frames = image::codecs::gif::GifDecoder::new(file)
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        assert_eq!(frames.len(), order.len());
+        assert_eq!(frames[0].buffer().dimensions(), (12, 12));
+        assert_eq!(frames[0].delay().numer_denom_ms(), (50, 1));
+
+        // the merge on the 4th insert recolors (1, 0) to match (0, 0)
+        let pixel = |frame: usize, px: u32| frames[frame].buffer().get_pixel(px, 10).0;
+        assert_ne!(pixel(2, 2), pixel(2, 6));
+        assert_eq!(pixel(3, 2), pixel(3, 6));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_to_json() {
+        let mask = "
+            #..
+            ##.
+            ##.
+        ";
+        let pumpkins = PumpkinPatch::from_mask_str(mask, Rc::new(LookupTable::new(3))).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&pumpkins.to_json()).unwrap();
+        assert_eq!(json["size"], 3);
+        assert!(json.get("width").is_none() && json.get("height").is_none());
+        assert_eq!(
+            json["cells"],
+            serde_json::json!([[7, 0, 0], [1, 1, 0], [1, 1, 0]])
+        );
+        assert_eq!(
+            json["pumpkins"].as_array().unwrap().len(),
+            pumpkins.pumpkin_count()
+        );
+        assert_eq!(
+            json["pumpkins"][0],
+            serde_json::json!({ "id": 1, "x": 0, "y": 0, "size": 2 })
+        );
+
+        let pumpkins = PumpkinPatch::new_rect(3, 2, Rc::new(LookupTable::new_rect(3, 2)));
+        let json: serde_json::Value = serde_json::from_str(&pumpkins.to_json()).unwrap();
+        assert_eq!((&json["width"], &json["height"]), (&3.into(), &2.into()));
+        assert!(json.get("size").is_none());
+    }
+
+    #[test]
+    fn test_to_svg() {
+        let mut pumpkins = PumpkinPatch::new_make_table(2);
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        let svg = pumpkins.to_svg();
+        assert!(svg.starts_with("<svg") && svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<rect").count(), 1);
+        assert!(svg.contains(r#"<rect x="0" y="0" width="40" height="40""#));
+        assert!(svg.contains(">1</text>"));
+
+        // y = 0 is drawn at the bottom
+        let mut pumpkins = PumpkinPatch::new_make_table(3);
+        pumpkins.add(2, 0).unwrap();
+        pumpkins.add(0, 2).unwrap();
+        let svg = pumpkins.to_svg();
+        assert_eq!(svg.matches("<rect").count(), 2);
+        assert!(svg.contains(r#"<rect x="40" y="40" width="20" height="20""#));
+        assert!(svg.contains(r#"<rect x="0" y="0" width="20" height="20""#));
+
+        let table = Rc::new(LookupTable::with_topology(3, 3, Topology::Toroidal));
+        let mut pumpkins = PumpkinPatch::new(3, table);
+        for (x, y) in [(2, 2), (0, 2), (2, 0), (0, 0)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        let svg = pumpkins.to_svg();
+        assert!(svg.contains(r#"<rect x="40" y="-20" width="40" height="40""#));
+    }
+
+    #[test]
+    fn test_display_width() {
+        let mut pumpkins = PumpkinPatch::new(40, Rc::new(LookupTable::new_capped(40, 1)));
+        pumpkins.add(0, 0).unwrap();
+        assert_eq!(pumpkins.display_width(), 3);
+        assert!(pumpkins
+            .to_string()
+            .lines()
+            .all(|line| line.len() == 40 * 4));
+
+        // ids past 999 widen every column
+        pumpkins.add(39, 39).unwrap();
+        pumpkins.add(24, 24).unwrap();
+        assert_eq!(pumpkins.display_width(), 4);
+        let display = pumpkins.to_string();
+        assert!(display.lines().all(|line| line.len() == 40 * 5));
+        assert!(display.starts_with(&"   0 ".repeat(39)));
+        assert!(display.lines().next().unwrap().ends_with("1600 "));
+        assert!(display.lines().last().unwrap().starts_with("   1    0 "));
+    }
+
+    #[test]
+    fn test_render_mask() {
+        let mut pumpkins = PumpkinPatch::new_rect(4, 3, Rc::new(LookupTable::new_rect(4, 3)));
+        assert_eq!(pumpkins.render_mask(), "....\n....\n....\n");
+
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1), (3, 0), (3, 2)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        assert_eq!(pumpkins.render_mask(), "...#\n##..\n##.#\n");
+
+        pumpkins.remove(1, 1);
+        assert_eq!(pumpkins.render_mask(), "...#\n#...\n##.#\n");
+    }
+
+    #[test]
+    fn test_from_mask_str() {
+        let l_shape = "#..\n#..\n###\n";
+        let pumpkins = PumpkinPatch::from_mask_str(l_shape, Rc::new(LookupTable::new(3))).unwrap();
+        assert_eq!(pumpkins.render_mask(), l_shape);
+        assert_eq!(pumpkins.filled_cells(), 5);
+        assert_eq!(pumpkins.pumpkin_count(), 5);
+
+        // indented masks and rectangular grids work too
+        let mask = "
+            ##.
+            ##.
+        ";
+        let pumpkins =
+            PumpkinPatch::from_mask_str(mask, Rc::new(LookupTable::new_rect(3, 2))).unwrap();
+        assert_eq!(pumpkins.get_square_at(1, 1), Some(Square::new(0, 0, 2)));
+
+        let table = Rc::new(LookupTable::new(3));
+        let parse = |s: &str| PumpkinPatch::from_mask_str(s, table.clone()).map(|_| ());
+        assert_eq!(parse(""), Err(ParseError::Empty));
+        assert_eq!(
+            parse("#..\n#.\n###"),
+            Err(ParseError::Ragged {
+                row: 1,
+                expected: 3,
+                found: 2
+            })
+        );
+        assert_eq!(
+            parse("#..\n#x.\n###"),
+            Err(ParseError::InvalidChar {
+                row: 1,
+                col: 1,
+                found: 'x'
+            })
+        );
+        assert_eq!(
+            parse("##\n##"),
+            Err(ParseError::TableMismatch {
+                width: 3,
+                height: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_from_bool_grid() {
+        let table = Rc::new(LookupTable::new(2));
+        let pumpkins =
+            PumpkinPatch::from_bool_grid(&vec![vec![true; 2]; 2], table.clone()).unwrap();
+        assert_eq!(pumpkins.pumpkin_count(), 1);
+        assert_eq!(pumpkins.get_square_at(1, 1), Some(Square::new(0, 0, 2)));
+
+        // the first row is the top one, like in masks
+        let grid = [vec![true, false], vec![true, true]];
+        let pumpkins = PumpkinPatch::from_bool_grid(&grid, table.clone()).unwrap();
+        assert_eq!(pumpkins.render_mask(), "#.\n##\n");
+
+        let parse =
+            |grid: &[Vec<bool>]| PumpkinPatch::from_bool_grid(grid, table.clone()).map(|_| ());
+        assert_eq!(parse(&[]), Err(ParseError::Empty));
+        assert_eq!(parse(&[vec![]]), Err(ParseError::Empty));
+        assert_eq!(
+            parse(&[vec![true; 2], vec![true]]),
+            Err(ParseError::Ragged {
+                row: 1,
+                expected: 2,
+                found: 1
+            })
+        );
+        assert_eq!(
+            parse(&vec![vec![true; 3]; 3]),
+            Err(ParseError::TableMismatch {
+                width: 2,
+                height: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_rle() {
+        let table = Rc::new(LookupTable::new(10));
+        let mut pumpkins = PumpkinPatch::new(10, table.clone());
+        assert_eq!(pumpkins.to_rle(), "100:0");
+
+        pumpkins.add(0, 0).unwrap();
+        assert_eq!(pumpkins.to_rle(), "1:1 99:0");
+
+        for (x, y) in [(5, 5), (6, 5), (5, 6), (6, 6), (9, 9), (3, 0)] {
+            pumpkins.add(x, y).unwrap();
+        }
+        let rle = pumpkins.to_rle();
+        assert_eq!(rle, "1:1 2:0 1:4 51:0 2:56 8:0 2:56 32:0 1:100");
+        let decoded = PumpkinPatch::from_rle(&rle, 10, table.clone()).unwrap();
+        assert_eq!(decoded, pumpkins);
+        assert_eq!(decoded.filled_cells(), 7);
+
+        let parse =
+            |s: &str| PumpkinPatch::from_rle(s, 2, Rc::new(LookupTable::new(2))).map(|_| ());
+        assert_eq!(parse("4:0"), Ok(()));
+        assert_eq!(parse("4:1"), Ok(()));
+        assert_eq!(parse("1:0 x:1"), Err(ParseError::InvalidRun { run: 1 }));
+        assert_eq!(parse("0:0 4:0"), Err(ParseError::InvalidRun { run: 0 }));
+        assert_eq!(
+            parse("3:0"),
+            Err(ParseError::CellCount {
+                expected: 4,
+                found: 3
+            })
+        );
+        assert_eq!(
+            parse("3:0 2:0"),
+            Err(ParseError::CellCount {
+                expected: 4,
+                found: 5
+            })
+        );
+        // pumpkin 2 has to start at cell 2, pumpkin 1 has to be square
+        assert_eq!(parse("2:0 2:2"), Err(ParseError::InvalidIds));
+        assert_eq!(parse("1:1 3:0"), Ok(()));
+        assert_eq!(parse("2:1 2:0"), Err(ParseError::InvalidIds));
+        assert_eq!(
+            parse("1:0 18446744073709551615:0"),
+            Err(ParseError::CellCount {
+                expected: 4,
+                found: usize::MAX
+            })
+        );
+        assert!(PumpkinPatch::from_rle("100:0", 10, Rc::new(LookupTable::new(9))).is_err());
+    }
+
+    #[test]
+    fn test_render_ansi() {
+        let mut pumpkins = PumpkinPatch::new_rect(3, 2, Rc::new(LookupTable::new_rect(3, 2)));
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1), (2, 1)] {
+            pumpkins.add(x, y).unwrap();
+        }
+
+        let ansi = pumpkins.render_ansi();
+        let rows: Vec<_> = ansi.lines().collect();
+        assert_eq!(rows.len(), 2);
+        assert!(ansi.contains("\x1b["));
+        // 5 filled cells, each colored and reset
+        assert_eq!(ansi.matches("\x1b[0m").count(), 5);
+        // the empty cell at (2, 0) is blank at the end of the bottom row
+        assert!(rows[1].ends_with("\x1b[0m  "));
+
+        // cells of one pumpkin share a color
+        let colored: Vec<_> = rows[1].split("\x1b[0m").take(2).collect();
+        assert_eq!(colored[0], colored[1]);
+    }
+
+    #[test]
+    fn test_shared_table_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<LookupTable>();
+        assert_send_sync::<PumpkinPatch<std::sync::Arc<LookupTable>>>();
+
+        let size = 8;
+        let table = std::sync::Arc::new(LookupTable::new(size));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let table = table.clone();
+                std::thread::spawn(move || {
+                    let mut pumpkins = PumpkinPatch::new(size, table);
+                    let squares = pumpkins.random_fill(&mut rand::thread_rng());
+                    assert_eq!(squares.len(), (size * size) as usize);
+                    *squares.last().unwrap()
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), Square::new(0, 0, size));
+        }
+    }
+
+    #[test]
+    fn test_lookup_table_adjacency() {
+        let table = LookupTable::new(4);
+
+        let sq = Square::new(1, 2, 1);
+        assert_eq!(table.get_smaller(sq), None);
+        assert_eq!(
+            table.get_larger(sq).collect::<Vec<_>>(),
+            [
+                Square::new(0, 1, 2),
+                Square::new(0, 2, 2),
+                Square::new(1, 1, 2),
+                Square::new(1, 2, 2),
+            ]
+        );
+
+        let sq = Square::new(0, 0, 2);
+        assert_eq!(
+            table.get_smaller(sq),
+            Some(&[
+                Square::new(0, 0, 1),
+                Square::new(1, 0, 1),
+                Square::new(0, 1, 1),
+                Square::new(1, 1, 1),
+            ])
+        );
+        assert_eq!(
+            table.get_larger(sq).collect::<Vec<_>>(),
+            [Square::new(0, 0, 3)]
+        );
+        assert_eq!(table.get_larger(Square::new(0, 0, 4)).len(), 0);
+    }
+
+    #[test]
+    fn test_lookup_table_to_dot() {
+        let dot = LookupTable::new(2).to_dot(2);
+        assert!(dot.starts_with("digraph squares {\n") && dot.ends_with("}\n"));
+        // 4 single cells and the full grid, each cell grows into the full grid
+        assert_eq!(
+            dot.lines()
+                .filter(|l| l.ends_with(';') && !l.contains("->"))
+                .count(),
+            5
+        );
+        assert_eq!(dot.matches("->").count(), 4);
+        assert!(dot.contains(r#""1,1,1" -> "0,0,2";"#));
+
+        // limiting the size drops the larger squares and their edges
+        let dot = LookupTable::new(3).to_dot(1);
+        assert_eq!(dot.lines().count(), 9 + 2);
+        assert!(!dot.contains("->"));
+    }
+
+    #[test]
+    fn test_lookup_table_validate() {
+        for size in 1..=10 {
+            assert_eq!(LookupTable::new(size).validate(), Ok(()));
+            assert_eq!(LookupTable::new_lean(size).validate(), Ok(()));
+        }
+
+        let mut table = LookupTable::new(4);
+        table.index.swap(10, 11);
+        assert!(table.validate().unwrap_err().contains("index decreases"));
+
+        let mut table = LookupTable::new(4);
+        table.larger_squares[0] = (3, 3);
+        assert!(table.validate().is_err());
+
+        let mut table = LookupTable::new(4);
+        table.smaller_squares[16] = None;
+        assert!(table.validate().unwrap_err().contains("missing"));
+
+        let mut table = LookupTable::new(4);
+        table.bitmaps[5].set(0, true);
+        assert!(table.validate().unwrap_err().contains("bitmap"));
+    }
+
+    #[test]
+    fn test_compressed_larger_squares() {
+        for size in 1..=12 {
+            let table = LookupTable::new(size);
+            for idx in 0..(size as usize).pow(3) {
+                let sq = Square::from_index(idx, size as usize, size as usize);
+                if !sq.fits(size, size) {
+                    continue;
+                }
+                assert_eq!(
+                    table.get_larger(sq).collect::<Vec<_>>(),
+                    sq.next_larger_squares(size, size)
+                );
+            }
         }
     }
 
@@ -508,12 +5828,40 @@ mod tests {
             for x in 0..size {
                 for y in 0..size {
                     let square = Square::new(x, y, size);
-                    let idx = square.idx(10);
-                    let sq = Square::from_index(idx, 10);
+                    let idx = square.idx(10, 10);
+                    let sq = Square::from_index(idx, 10, 10);
 
                     assert_eq!(square, sq)
                 }
             }
         }
     }
+
+    #[test]
+    fn idx_round_trip() {
+        let grid_size = 64;
+        for idx in 0..grid_size * grid_size * grid_size {
+            let sq = Square::from_index(idx, grid_size, grid_size);
+            assert_eq!(sq.idx(grid_size, grid_size), idx);
+        }
+    }
+
+    #[test]
+    fn idx_bounds() {
+        for grid_size in [1, 2, 17, 64, 255] {
+            let gz = grid_size as usize;
+
+            // the grid filling square is the first square of the largest size
+            let full = Square::new(0, 0, grid_size);
+            assert_eq!(full.idx(gz, gz), (gz - 1) * gz * gz);
+            assert_eq!(Square::from_index(full.idx(gz, gz), gz, gz), full);
+
+            // the last index belongs to the largest square in the far corner
+            let last = Square::new(grid_size - 1, grid_size - 1, grid_size);
+            assert_eq!(last.idx(gz, gz), gz * gz * gz - 1);
+            assert_eq!(Square::from_index(gz * gz * gz - 1, gz, gz), last);
+
+            assert_eq!(Square::from_index(0, gz, gz), Square::new(0, 0, 1));
+        }
+    }
 }
@@ -0,0 +1,39 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+#![allow(dead_code)]
+
+extern crate alloc;
+
+mod bitmap;
+pub mod graph;
+pub mod rect;
+
+// Drives the library through the API a `no_std` user has, importing only from `core` and
+// `alloc`. Tests always link `std`, so `cargo build --no-default-features` is what checks the
+// library itself builds without it.
+#[cfg(test)]
+mod no_std_smoke {
+    use alloc::{rc::Rc, vec::Vec};
+
+    use crate::graph::{LookupTable, PumpkinPatch, Square};
+    use crate::rect::{Rect, RectPatch};
+
+    #[test]
+    fn test_fill() {
+        let size = 5;
+        let mut pumpkins = PumpkinPatch::new(size, Rc::new(LookupTable::new(size)));
+        // a fixed scrambled order, `rand` needs `std`
+        let order: Vec<u16> = (0..size * size).map(|i| (i * 7) % (size * size)).collect();
+        for idx in order {
+            pumpkins.add(idx % size, idx / size).unwrap();
+            assert_eq!(pumpkins.validate(), Ok(()));
+        }
+        assert_eq!(pumpkins.get_square_at(0, 0), Some(Square::new(0, 0, size)));
+        assert!(pumpkins.to_csv().starts_with("id,x,y,size,cells"));
+
+        let mut rects = RectPatch::new(2, 3);
+        for idx in 0..6 {
+            rects.add(idx % 2, idx / 2).unwrap();
+        }
+        assert_eq!(rects.get_rect_at(1, 2), Some(Rect::new(0, 0, 2, 3)));
+    }
+}
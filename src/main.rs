@@ -1,29 +1,27 @@
 #![allow(dead_code)]
 
 use std::rc::Rc;
+#[cfg(feature = "rayon")]
+use std::sync::Arc;
 
-use graph::{LookupTable, PumpkinPatch};
-use rand::seq::SliceRandom;
+use pumpkins::graph::{LookupTable, PumpkinPatch};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 
-pub mod graph;
-
-fn interactive(size: u16) {
+fn interactive(size: u16, rng: &mut impl Rng) {
     let start = std::time::Instant::now();
     let elapsed = start.elapsed();
     println!("Built lookup table in {:?}", elapsed);
 
     let lookup_table = Rc::new(LookupTable::new(size));
     let mut pumpkins = PumpkinPatch::new(size, lookup_table);
-    let mut order = (0..size * size).collect::<Vec<_>>();
-    order.shuffle(&mut rand::thread_rng());
+    let order = shuffled(pumpkins.empty_cells(), rng);
 
-    for idx in order {
-        let (x, y) = (idx % size, idx / size);
+    for (i, (x, y)) in order.into_iter().enumerate() {
         println!(
             "Insert: {} / {:?} | {:?}",
-            idx + 1,
+            i + 1,
             (x, y),
-            pumpkins.add(x, y)
+            pumpkins.add(x, y).unwrap()
         );
         print!("{}", pumpkins);
         // wait for user input
@@ -32,42 +30,251 @@ fn interactive(size: u16) {
     }
 }
 
-fn benchmark(size: u16) {
-    const N: usize = 5;
+// Number of random orders each benchmark fills the grid with
+const N: usize = 5;
+
+// Time taken by `benchmark` to fill a `size` x `size` grid `N` times
+struct Timing {
+    size: u16,
+    elapsed: std::time::Duration,
+}
+
+impl Timing {
+    fn print(&self) {
+        println!(
+            "Size {}x{} - Elapsed: {:?} - ΔT: {:?}",
+            self.size,
+            self.size,
+            self.elapsed / N as u32,
+            self.elapsed / (N * N * N) as u32
+        );
+    }
+
+    // `size,total_ns,per_insert_ns` where total is the average time of one fill
+    fn print_csv(&self) {
+        let total = self.elapsed.as_nanos() / N as u128;
+        let cells = self.size as u128 * self.size as u128;
+        println!("{},{},{}", self.size, total, total / cells);
+    }
+}
+
+// The cells in a random order, `PumpkinPatch::random_fill` does the same but also adds them
+fn shuffled(cells: impl Iterator<Item = (u16, u16)>, rng: &mut impl Rng) -> Vec<(u16, u16)> {
+    let mut order: Vec<_> = cells.collect();
+    order.shuffle(rng);
+    order
+}
+
+// `N` random insert orders for a `size` x `size` grid, drawn up front so serial and parallel
+// benchmarks with the same seed fill the grid the same way
+fn sample_orders(size: u16, rng: &mut impl Rng) -> Vec<Vec<(u16, u16)>> {
+    (0..N)
+        .map(|_| shuffled((0..size * size).map(|idx| (idx % size, idx / size)), rng))
+        .collect()
+}
 
-    // benchmark - run 1000 random orderings
+fn benchmark(size: u16, rng: &mut impl Rng) -> Timing {
     let lookup_table = Rc::new(LookupTable::new(size));
-    let mut rng = rand::thread_rng();
-    let samples = (0..N).map(|_| {
-        let mut order = (0..size * size).collect::<Vec<_>>();
-        order.shuffle(&mut rng);
-        order
-    });
+    let samples = sample_orders(size, rng);
 
+    let mut pumpkins = PumpkinPatch::new(size, lookup_table);
     let start = std::time::Instant::now();
     for order in samples {
-        let mut pumpkins = PumpkinPatch::new(size, lookup_table.clone());
-        for idx in order {
-            let (x, y) = (idx % size, idx / size);
-            pumpkins.add(x, y);
+        pumpkins.clear();
+        for (x, y) in order {
+            pumpkins.add(x, y).unwrap();
         }
     }
-    let elapsed = start.elapsed();
-    println!(
-        "Size {}x{} - Elapsed: {:?} - ΔT: {:?}",
+    Timing {
         size,
-        size,
-        elapsed / N as u32,
-        elapsed / (N * N * N) as u32
-    );
+        elapsed: start.elapsed(),
+    }
+}
+
+// `benchmark` running the fills on the rayon thread pool, sharing one table. `elapsed` adds up
+// the time of each fill so it stays comparable with the serial numbers.
+#[cfg(feature = "rayon")]
+fn benchmark_parallel(size: u16, rng: &mut impl Rng) -> Timing {
+    use rayon::prelude::*;
+
+    let lookup_table = Arc::new(LookupTable::new(size));
+    let elapsed = sample_orders(size, rng)
+        .par_iter()
+        .map(|order| {
+            let mut pumpkins = PumpkinPatch::new(size, lookup_table.clone());
+            let start = std::time::Instant::now();
+            for &(x, y) in order {
+                pumpkins.add(x, y).unwrap();
+            }
+            start.elapsed()
+        })
+        .sum();
+
+    Timing { size, elapsed }
+}
+
+const USAGE: &str = "usage: pumpkins [--interactive] [--size N] [--seed S]
+       pumpkins [--bench] [--sizes N,N,...] [--seed S] [--csv] [--parallel]
+
+Without a mode debug builds run interactively and release builds benchmark.
+--seed makes the shuffled insert orders reproducible, --csv prints the benchmark results as
+size,total_ns,per_insert_ns rows. --parallel runs the fills of each size on all cores, it
+needs the rayon feature.";
+
+enum Mode {
+    Interactive(u16),
+    Bench(Vec<u16>),
+}
+
+struct Options {
+    mode: Mode,
+    seed: Option<u64>,
+    csv: bool,
+    parallel: bool,
+}
+
+// Parses the arguments after the program name, falling back to the defaults for anything missing
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut interactive = cfg!(debug_assertions);
+    let mut sizes = None;
+    let mut seed = None;
+    let mut csv = false;
+    let mut parallel = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--interactive" => interactive = true,
+            "--bench" => interactive = false,
+            "--csv" => csv = true,
+            "--parallel" if cfg!(feature = "rayon") => parallel = true,
+            "--parallel" => return Err("--parallel needs the rayon feature".to_string()),
+            "--size" | "--sizes" => {
+                let value = args.next().ok_or(format!("{} needs a value", arg))?;
+                let parsed = value
+                    .split(',')
+                    .map(|size| match size.trim().parse::<u16>() {
+                        Ok(size) if size > 0 => Ok(size),
+                        _ => Err(format!("invalid size {:?}", size)),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                sizes = Some(parsed);
+            }
+            "--seed" => {
+                let value = args.next().ok_or("--seed needs a value")?;
+                seed = Some(
+                    value
+                        .parse::<u64>()
+                        .map_err(|_| format!("invalid seed {:?}", value))?,
+                );
+            }
+            _ => return Err(format!("unknown argument {:?}", arg)),
+        }
+    }
+
+    let mode = if interactive {
+        if csv || parallel {
+            return Err("--csv and --parallel only apply to benchmarks".to_string());
+        }
+        match sizes.as_deref() {
+            None => Mode::Interactive(20),
+            Some(&[size]) => Mode::Interactive(size),
+            Some(_) => return Err("interactive mode takes a single size".to_string()),
+        }
+    } else {
+        Mode::Bench(sizes.unwrap_or_else(|| vec![10, 20, 30, 40, 50, 60, 70, 80]))
+    };
+
+    Ok(Options {
+        mode,
+        seed,
+        csv,
+        parallel,
+    })
 }
 
 fn main() {
-    #[cfg(debug_assertions)]
-    interactive(20);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let options = match parse_args(args.into_iter()) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
 
-    #[cfg(not(debug_assertions))]
-    for sz in [10, 20, 30, 40, 50, 60, 70, 80] {
-        benchmark(sz)
+    // Without a seed the orders come from the thread rng, so every run is different
+    let mut rng = match options.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_rng(rand::thread_rng()).unwrap(),
+    };
+
+    match options.mode {
+        Mode::Interactive(size) => interactive(size, &mut rng),
+        Mode::Bench(sizes) => {
+            if options.csv {
+                println!("size,total_ns,per_insert_ns");
+            }
+            for size in sizes {
+                #[cfg(feature = "rayon")]
+                let timing = if options.parallel {
+                    benchmark_parallel(size, &mut rng)
+                } else {
+                    benchmark(size, &mut rng)
+                };
+                #[cfg(not(feature = "rayon"))]
+                let timing = benchmark(size, &mut rng);
+                if options.csv {
+                    timing.print_csv();
+                } else {
+                    timing.print();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_parallel_fills_match_serial() {
+        use rayon::prelude::*;
+
+        let size = 12;
+        let orders = sample_orders(size, &mut StdRng::seed_from_u64(7));
+        assert_eq!(orders, sample_orders(size, &mut StdRng::seed_from_u64(7)));
+
+        let rc_table = Rc::new(LookupTable::new(size));
+        let serial: Vec<_> = orders
+            .iter()
+            .map(|order| PumpkinPatch::replay(size, order, rc_table.clone()))
+            .collect();
+
+        let arc_table = Arc::new(LookupTable::new(size));
+        let parallel: Vec<_> = orders
+            .par_iter()
+            .map(|order| PumpkinPatch::replay(size, order, arc_table.clone()))
+            .collect();
+
+        assert_eq!(serial, parallel);
+    }
+
+    #[test]
+    fn test_parse_parallel() {
+        let args = |args: &[&str]| parse_args(args.iter().map(|arg| arg.to_string()));
+        let parsed = args(&["--bench", "--parallel"]);
+        if cfg!(feature = "rayon") {
+            assert!(parsed.unwrap().parallel);
+        } else {
+            assert!(parsed.is_err());
+        }
+        assert!(args(&["--interactive", "--parallel"]).is_err());
     }
 }
@@ -0,0 +1,512 @@
+use alloc::{format, string::String, vec, vec::Vec};
+use core::num::{NonZeroU16, NonZeroU32};
+
+use crate::bitmap::Bitmap;
+use crate::graph::{id_column_width, pumpkin_id, AddError, Square};
+
+/// An axis-aligned `width` x `height` block of cells, the shape of a pumpkin in a [`RectPatch`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rect {
+    x: u16,
+    y: u16,
+    width: NonZeroU16,
+    height: NonZeroU16,
+}
+
+impl Rect {
+    /// Creates a new rectangle with its bottom-left cell at `(x, y)`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `width` or `height` is 0
+    pub fn new(x: u16, y: u16, width: u16, height: u16) -> Self {
+        Self {
+            x,
+            y,
+            width: NonZeroU16::new(width).expect("rect width must be non-zero"),
+            height: NonZeroU16::new(height).expect("rect height must be non-zero"),
+        }
+    }
+
+    pub fn x(&self) -> u16 {
+        self.x
+    }
+
+    pub fn y(&self) -> u16 {
+        self.y
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width.get()
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height.get()
+    }
+
+    /// Number of grid cells covered by the rectangle
+    pub fn area(&self) -> u32 {
+        self.width() as u32 * self.height() as u32
+    }
+
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        self.x <= x && x < self.x + self.width() && self.y <= y && y < self.y + self.height()
+    }
+
+    /// Returns true if the rectangle lies entirely inside a `width` x `height` grid
+    pub fn fits(&self, width: u16, height: u16) -> bool {
+        self.x as u32 + self.width() as u32 <= width as u32
+            && self.y as u32 + self.height() as u32 <= height as u32
+    }
+
+    /// Iterates over every cell covered by the rectangle in row-major order
+    pub fn iter_cells(&self) -> impl Iterator<Item = (u16, u16)> {
+        let (x0, y0, w, h) = (self.x, self.y, self.width(), self.height());
+        (y0..y0 + h).flat_map(move |y| (x0..x0 + w).map(move |x| (x, y)))
+    }
+
+    /// The rectangles one cell wider or taller that contain this one and fit inside a
+    /// `width` x `height` grid, in the order east, west, north, south
+    pub fn next_larger_rects(&self, width: u16, height: u16) -> impl Iterator<Item = Rect> {
+        let (x, y, w, h) = (self.x, self.y, self.width(), self.height());
+        [
+            Some(Rect::new(x, y, w + 1, h)),
+            x.checked_sub(1).map(|x| Rect::new(x, y, w + 1, h)),
+            Some(Rect::new(x, y, w, h + 1)),
+            y.checked_sub(1).map(|y| Rect::new(x, y, w, h + 1)),
+        ]
+        .into_iter()
+        .flatten()
+        .filter(move |rect| rect.fits(width, height))
+    }
+
+    // Minimal perfect hash of the rectangles fitting in a `width` x `height` grid into
+    // `0..rect_count(width, height)`. The horizontal and vertical extents are numbered
+    // separately, extents of length `len` coming after the shorter ones.
+    fn idx(&self, width: usize, height: usize) -> usize {
+        let columns = extent_idx(self.x as usize, self.width() as usize, width);
+        let rows = extent_idx(self.y as usize, self.height() as usize, height);
+        columns * extent_count(height) + rows
+    }
+}
+
+// Index of the extent `start..start + len` among those fitting in `0..grid`, there are
+// `grid - k + 1` extents of each shorter length `k`
+fn extent_idx(start: usize, len: usize, grid: usize) -> usize {
+    let shorter = len - 1;
+    shorter * (grid + 1) - shorter * (shorter + 1) / 2 + start
+}
+
+// Number of extents fitting in `0..grid`
+fn extent_count(grid: usize) -> usize {
+    grid * (grid + 1) / 2
+}
+
+// Number of slots used by `Rect::idx`
+fn rect_count(width: usize, height: usize) -> usize {
+    extent_count(width) * extent_count(height)
+}
+
+impl From<Square> for Rect {
+    fn from(square: Square) -> Self {
+        Rect::new(square.x(), square.y(), square.size(), square.size())
+    }
+}
+
+/// A grid where pumpkins grow into rectangles instead of squares
+///
+/// `add` greedily merges a cell into the largest-area rectangle of filled cells around it that
+/// doesn't cut through another pumpkin, just like [`crate::graph::PumpkinPatch`] does with
+/// squares. Rectangles have no precomputed table, their neighbors are found on the fly and the
+/// search scales with the number of filled rectangles around the cell, which is much larger
+/// than the number of squares on big grids.
+///
+/// The first `add` allocates one bit for every rectangle fitting in the grid to mark the ones
+/// the search has seen, about `W² H² / 4` bits: 3 MB on a 100x100 grid and 50 MB on a 200x200
+/// one.
+#[derive(Debug, Clone)]
+pub struct RectPatch {
+    bitmap: Bitmap,
+    ids: Vec<Option<NonZeroU32>>,
+    width: u16,
+    height: u16,
+
+    // Number of set bits in `bitmap`
+    filled: usize,
+
+    // Buffers reused by every `add` so the search doesn't allocate
+    search: RectSearch,
+}
+
+// Working memory of `RectPatch::largest_rect`, see `Search` in `graph`
+#[derive(Debug, Clone, Default)]
+struct RectSearch {
+    // One bit per `Rect::idx` slot, only the bits listed in `touched` are ever set
+    visited: Bitmap,
+    touched: Vec<usize>,
+    stack: Vec<Rect>,
+}
+
+impl RectPatch {
+    /// Creates an empty `width` x `height` patch
+    pub fn new(width: u16, height: u16) -> Self {
+        let cells = width as usize * height as usize;
+        Self {
+            bitmap: Bitmap::new(cells),
+            ids: vec![None; cells],
+            width,
+            height,
+            filled: 0,
+            search: RectSearch::default(),
+        }
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /// Returns the id of the pumpkin covering (x, y), `None` if the cell is empty or outside of
+    /// the grid
+    pub fn get(&self, x: u16, y: u16) -> Option<NonZeroU32> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        self.ids[self.index(x, y)]
+    }
+
+    /// Returns true if (x, y) is filled, cells outside of the grid never are
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        x < self.width && y < self.height && self.bitmap[self.index(x, y)]
+    }
+
+    /// Number of filled cells
+    pub fn filled_cells(&self) -> usize {
+        self.filled
+    }
+
+    /// Returns the rectangle of the pumpkin covering (x, y), `None` if the cell is empty or
+    /// outside of the grid
+    pub fn get_rect_at(&self, x: u16, y: u16) -> Option<Rect> {
+        self.get(x, y).map(|id| self.pumpkin_rect(id))
+    }
+
+    /// Iterates over every pumpkin in the patch along with its rectangle, in row-major order of
+    /// the pumpkins' origins
+    pub fn pumpkins(&self) -> impl Iterator<Item = (NonZeroU32, Rect)> + '_ {
+        self.ids.iter().enumerate().filter_map(|(idx, id)| {
+            let id = (*id)?;
+            (id.get() as usize - 1 == idx).then(|| (id, self.pumpkin_rect(id)))
+        })
+    }
+
+    /// Number of distinct pumpkins in the patch
+    pub fn pumpkin_count(&self) -> usize {
+        self.pumpkins().count()
+    }
+
+    /// Fills the cell at (x, y) and merges it into the largest pumpkin it can form
+    pub fn add(&mut self, x: u16, y: u16) -> Result<Rect, AddError> {
+        if x >= self.width || y >= self.height {
+            return Err(AddError::OutOfBounds);
+        }
+        if self.contains(x, y) {
+            return Err(AddError::AlreadyFilled);
+        }
+
+        let mut search = core::mem::take(&mut self.search);
+        let rect = self.largest_rect(x, y, &mut search);
+        self.search = search;
+
+        let idx = self.index(x, y);
+        self.bitmap.set(idx, true);
+        self.filled += 1;
+
+        let id = pumpkin_id(rect.x, rect.y, self.width);
+        for (cx, cy) in rect.iter_cells() {
+            let idx = self.index(cx, cy);
+            self.ids[idx] = Some(id);
+        }
+
+        Ok(rect)
+    }
+
+    /// Checks the patch's internal invariants, describing the first violation found
+    ///
+    /// A cell has to be filled exactly when it has an id, and the cells sharing an id have to
+    /// form exactly the rectangle of that pumpkin.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut cells_per_id = alloc::collections::BTreeMap::new();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let id = self.get(x, y);
+                if id.is_some() != self.contains(x, y) {
+                    return Err(format!(
+                        "cell ({}, {}) has id {:?} but filled is {}",
+                        x,
+                        y,
+                        id,
+                        self.contains(x, y)
+                    ));
+                }
+                if let Some(id) = id {
+                    *cells_per_id.entry(id).or_insert(0u32) += 1;
+                }
+            }
+        }
+
+        if self.bitmap.count_ones() != self.filled {
+            return Err(format!(
+                "{} cells are filled but the count is {}",
+                self.bitmap.count_ones(),
+                self.filled
+            ));
+        }
+
+        for (id, count) in cells_per_id {
+            if self.ids.get(id.get() as usize - 1) != Some(&Some(id)) {
+                return Err(format!("pumpkin {} doesn't cover its origin cell", id));
+            }
+
+            let rect = self.pumpkin_rect(id);
+            if let Some((cx, cy)) = rect
+                .iter_cells()
+                .find(|&(cx, cy)| self.get(cx, cy) != Some(id))
+            {
+                return Err(format!(
+                    "cell ({}, {}) is inside pumpkin {} but has id {:?}",
+                    cx,
+                    cy,
+                    id,
+                    self.get(cx, cy)
+                ));
+            }
+            if count != rect.area() {
+                return Err(format!(
+                    "pumpkin {} is {:?} but {} cells have its id",
+                    id, rect, count
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn index(&self, x: u16, y: u16) -> usize {
+        y as usize * self.width as usize + x as usize
+    }
+
+    // Reconstructs the rectangle of the pumpkin with the given id. Ids encode the pumpkin's
+    // origin, the width and height are found by walking east and north from there.
+    fn pumpkin_rect(&self, id: NonZeroU32) -> Rect {
+        let origin = id.get() - 1;
+        let width = self.width as u32;
+        let (x, y) = ((origin % width) as u16, (origin / width) as u16);
+        debug_assert_eq!(self.get(x, y), Some(id));
+
+        let w = (x..self.width)
+            .take_while(|&cx| self.get(cx, y) == Some(id))
+            .count();
+        let h = (y..self.height)
+            .take_while(|&cy| self.get(x, cy) == Some(id))
+            .count();
+
+        Rect::new(x, y, w as u16, h as u16)
+    }
+
+    // DFS over the rectangles containing (x, y) whose other cells are filled, returning the
+    // largest by area that passes `check_boundary`. (x, y) is treated as filled.
+    fn largest_rect(&self, x: u16, y: u16, search: &mut RectSearch) -> Rect {
+        let start = Rect::new(x, y, 1, 1);
+        let mut largest_rect = start;
+
+        let (width, height) = (self.width as usize, self.height as usize);
+        let RectSearch {
+            visited,
+            touched,
+            stack,
+        } = search;
+        if visited.len() != rect_count(width, height) {
+            *visited = Bitmap::new(rect_count(width, height));
+        }
+        debug_assert!(touched.is_empty() && stack.is_empty());
+
+        visited.set(start.idx(width, height), true);
+        touched.push(start.idx(width, height));
+        stack.push(start);
+
+        while let Some(rect) = stack.pop() {
+            let filled = rect
+                .iter_cells()
+                .all(|(cx, cy)| (cx, cy) == (x, y) || self.contains(cx, cy));
+            if !filled {
+                continue;
+            }
+
+            for larger in rect.next_larger_rects(self.width, self.height) {
+                let idx = larger.idx(width, height);
+                if !visited[idx] {
+                    visited.set(idx, true);
+                    touched.push(idx);
+                    stack.push(larger);
+                }
+            }
+
+            if rect.area() > largest_rect.area() && self.check_boundary(&rect) {
+                largest_rect = rect;
+            }
+        }
+
+        for idx in touched.drain(..) {
+            visited.set(idx, false);
+        }
+
+        largest_rect
+    }
+
+    // Returns false if a pumpkin crosses any side of `rect`, the cell just outside a side
+    // sharing its id with the cell just inside
+    fn check_boundary(&self, rect: &Rect) -> bool {
+        let (x0, y0) = (rect.x as i32, rect.y as i32);
+        let (w, h) = (rect.width() as i32, rect.height() as i32);
+        let at = |x: i32, y: i32| {
+            let in_grid =
+                (0..self.width as i32).contains(&x) && (0..self.height as i32).contains(&y);
+            in_grid.then(|| self.get(x as u16, y as u16)).flatten()
+        };
+        let crosses = |inside: Option<NonZeroU32>, outside: Option<NonZeroU32>| {
+            outside.is_some() && inside == outside
+        };
+
+        // north and south
+        let rows = (x0..x0 + w).any(|x| {
+            crosses(at(x, y0 + h - 1), at(x, y0 + h)) || crosses(at(x, y0), at(x, y0 - 1))
+        });
+        // east and west
+        let columns = (y0..y0 + h).any(|y| {
+            crosses(at(x0 + w - 1, y), at(x0 + w, y)) || crosses(at(x0, y), at(x0 - 1, y))
+        });
+
+        !rows && !columns
+    }
+}
+
+impl core::fmt::Display for RectPatch {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // Pad to the widest id like `PumpkinPatch` does
+        let width = id_column_width(&self.ids);
+
+        // Print the ids in a grid, but reverse the order of the y direction
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                let id = self.get(x, y).map_or(0, |id| id.get());
+                write!(f, "{:width$} ", id)?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{LookupTable, PumpkinPatch};
+    use rand::seq::SliceRandom;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_rect_idx() {
+        let (width, height) = (4, 3);
+        let mut seen = std::collections::HashSet::new();
+        for h in 1..=height {
+            for w in 1..=width {
+                for y in 0..=height - h {
+                    for x in 0..=width - w {
+                        let idx = Rect::new(x, y, w, h).idx(width as usize, height as usize);
+                        assert!(idx < rect_count(width as usize, height as usize));
+                        assert!(seen.insert(idx), "{} is used twice", idx);
+                    }
+                }
+            }
+        }
+        // no slot is wasted
+        assert_eq!(seen.len(), rect_count(width as usize, height as usize));
+    }
+
+    #[test]
+    fn test_next_larger_rects() {
+        let larger: Vec<_> = Rect::new(1, 0, 1, 2).next_larger_rects(3, 3).collect();
+        assert_eq!(
+            larger,
+            [
+                Rect::new(1, 0, 2, 2),
+                Rect::new(0, 0, 2, 2),
+                Rect::new(1, 0, 1, 3)
+            ]
+        );
+        assert_eq!(Rect::new(0, 0, 2, 4).next_larger_rects(2, 4).count(), 0);
+    }
+
+    #[test]
+    fn test_rect_patch_2x4() {
+        let order: Vec<_> = (0..8).map(|i| (i % 2, i / 2)).collect();
+
+        let mut rects = RectPatch::new(2, 4);
+        for &(x, y) in &order {
+            rects.add(x, y).unwrap();
+            println!("{}", rects);
+            assert_eq!(rects.validate(), Ok(()));
+        }
+        assert_eq!(rects.pumpkin_count(), 1);
+        assert_eq!(rects.get_rect_at(1, 3), Some(Rect::new(0, 0, 2, 4)));
+
+        // squares can only cover it with two 2x2 pumpkins
+        let mut squares = PumpkinPatch::new_rect(2, 4, Rc::new(LookupTable::new_rect(2, 4)));
+        for &(x, y) in &order {
+            squares.add(x, y).unwrap();
+        }
+        assert_eq!(squares.pumpkin_count(), 2);
+    }
+
+    #[test]
+    fn test_rect_patch_fill() {
+        for (width, height) in [(1, 5), (3, 3), (4, 2), (5, 4)] {
+            let mut order: Vec<_> = (0..width * height)
+                .map(|i| (i % width, i / width))
+                .collect();
+            order.shuffle(&mut rand::thread_rng());
+
+            let mut rects = RectPatch::new(width, height);
+            for (x, y) in order {
+                let rect = rects.add(x, y).unwrap();
+                assert!(rect.contains(x, y));
+                assert_eq!(rects.validate(), Ok(()));
+            }
+            assert_eq!(rects.filled_cells(), (width * height) as usize);
+            assert_eq!(
+                rects.get_rect_at(0, 0),
+                Some(Rect::new(0, 0, width, height))
+            );
+            assert_eq!(rects.add(0, 0), Err(AddError::AlreadyFilled));
+            assert_eq!(rects.add(width, 0), Err(AddError::OutOfBounds));
+            assert_eq!(rects.get(width, 0), None);
+            assert!(!rects.contains(0, height));
+        }
+    }
+
+    #[test]
+    fn test_rect_boundary() {
+        // a 2x1 pumpkin in the bottom row blocks the 1x2 column through its right cell
+        let mut rects = RectPatch::new(2, 2);
+        rects.add(0, 0).unwrap();
+        assert_eq!(rects.add(1, 0), Ok(Rect::new(0, 0, 2, 1)));
+        assert_eq!(rects.add(1, 1), Ok(Rect::new(1, 1, 1, 1)));
+        assert_eq!(rects.add(0, 1), Ok(Rect::new(0, 0, 2, 2)));
+        assert_eq!(rects.validate(), Ok(()));
+    }
+}